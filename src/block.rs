@@ -0,0 +1,208 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Translation cache. Straight-line runs of guest code are decoded once into
+// blocks keyed by their starting pc and executed as a unit. A block ends at
+// the first control transfer, ecall or fence.i, or at the end of its page.
+// Each block remembers the last two blocks it went to, so hot loops go from
+// block to block without touching the hash map.
+//
+// Stores to a page holding translated code, and fence.i, throw away the whole
+// cache once the current block finishes. Code rewriting itself is rare enough
+// that anything finer is not worth the bookkeeping.
+//
+// With a JIT enabled, blocks that keep running are compiled to host code and
+// called directly from then on.
+
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crate::adt;
+use crate::coverage;
+use crate::crash;
+use crate::decode;
+use crate::decode::Instruction;
+use crate::interp;
+use crate::jit;
+use crate::jit::Jit;
+use crate::jit::NativeBlock;
+use crate::limits;
+use crate::STACK_OFFSET;
+use crate::mapped;
+use crate::offset;
+use crate::stack_size;
+
+/// Guest pages we can hold code for: program memory, the gap after it, then
+/// the stack
+pub(crate) const PAGES: usize = (STACK_OFFSET + stack_size) as usize >> 12;
+
+pub(crate) static CODE_PAGES: [AtomicU64; PAGES / 64] = [const { AtomicU64::new(0) }; PAGES / 64];
+pub(crate) static STALE: AtomicBool = AtomicBool::new(false);
+
+#[inline(always)]
+fn page_index(addr: u64) -> usize {
+    (offset(addr) >> 12) as usize
+}
+
+/// Note a guest store, marking the cache stale if it hits translated code.
+#[inline(always)]
+pub(crate) fn store(addr: u64) {
+    let page = page_index(addr);
+    if page < PAGES && CODE_PAGES[page / 64].load(Ordering::Relaxed) & (1 << (page % 64)) != 0 {
+        STALE.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Drop all translations before the next block runs (fence.i).
+pub(crate) fn invalidate() {
+    STALE.store(true, Ordering::Relaxed);
+}
+
+const NO_LINK: (u64, usize) = (u64::MAX, usize::MAX);
+
+struct Block {
+    start: u64,
+    /// As decoded, for the JIT and for stopping mid-block (empty without
+    /// either)
+    insts: Vec<Instruction>,
+    /// Guest instructions in the block
+    len: u64,
+    /// With idioms fused, for the interpreter
+    ops: Vec<Instruction>,
+    /// Times run, until it reaches jit::HOT and compilation is tried
+    hits: u32,
+    native: Option<NativeBlock>,
+    /// Successor pcs and their block indices, filled in as exits are taken
+    links: [(u64, usize); 2],
+}
+
+pub(crate) struct BlockCache {
+    blocks: Vec<Block>,
+    index: HashMap<u64, usize>,
+    jit: Option<Jit>,
+    /// Instructions retired so far
+    pub(crate) retired: u64,
+    max_insns: u64,
+}
+
+/// Why run() returned
+pub(crate) enum Stop {
+    Flushed,
+    InsnLimit,
+    Timeout,
+}
+
+impl BlockCache {
+    pub(crate) fn new(jit: Option<Jit>, max_insns: Option<u64>) -> Self {
+        Self {
+            blocks: Vec::new(),
+            index: HashMap::new(),
+            jit,
+            retired: 0,
+            max_insns: max_insns.unwrap_or(u64::MAX),
+        }
+    }
+
+    fn translate(&mut self, pc: u64, mem: *mut libc::c_void, registers: &[u64; 32]) -> usize {
+        let page = page_index(pc);
+        if !mapped(pc, 4) {
+            crash::report(libc::SIGSEGV, &format!("jump to unmapped address {:#x}", pc), mem, registers, pc);
+        }
+        CODE_PAGES[page / 64].fetch_or(1 << (page % 64), Ordering::Relaxed);
+        let mut insts = Vec::with_capacity(((0x1000 - (pc & 0xfff)) / 4).min(64) as usize);
+        let mut addr = pc;
+        loop {
+            let inst = decode::decode(unsafe { *(adt(addr, mem) as *const u32) });
+            insts.push(inst);
+            addr += 4;
+            if inst.ends_block() || addr & 0xfff == 0 {
+                break;
+            }
+        }
+        let len = insts.len() as u64;
+        let keep = self.jit.is_some() || self.max_insns != u64::MAX;
+        let mut ops = if keep { insts.clone() } else { std::mem::take(&mut insts) };
+        decode::fuse(&mut ops);
+        coverage::record(pc, len * 4);
+        self.blocks.push(Block { start: pc, insts, len, ops, hits: 0, native: None, links: [NO_LINK; 2] });
+        self.index.insert(pc, self.blocks.len() - 1);
+        self.blocks.len() - 1
+    }
+
+    fn flush(&mut self) {
+        self.blocks.clear();
+        self.index.clear();
+        if let Some(jit) = self.jit.as_mut() {
+            jit.clear();
+        }
+        for w in CODE_PAGES.iter() {
+            w.store(0, Ordering::Relaxed);
+        }
+        STALE.store(false, Ordering::Relaxed);
+    }
+
+    /// Run translated code from pc, going from block to block through the
+    /// links and translating as needed. Returns when the cache was flushed or
+    /// a limit was hit.
+    pub(crate) fn run(&mut self, mem: *mut libc::c_void, registers: &mut [u64; 32], guest_pc: &mut u64) -> Stop {
+        // kept local so it can live in a host register
+        let mut pc = *guest_pc;
+        let pc = &mut pc;
+        let mut b = match self.index.get(pc) {
+            Some(&b) => b,
+            None => self.translate(*pc, mem, registers),
+        };
+        loop {
+            let block = &mut self.blocks[b];
+            crash::CURRENT.store(block.start, Ordering::Relaxed);
+            if self.retired + block.len > self.max_insns {
+                // step up to the limit exactly, unfused so every one counts
+                for inst in block.insts[..(self.max_insns - self.retired) as usize].iter() {
+                    interp::execute(*inst, mem, registers, pc);
+                }
+                self.retired = self.max_insns;
+                *guest_pc = *pc;
+                return Stop::InsnLimit;
+            }
+            self.retired += block.len;
+            if let Some(f) = block.native {
+                *pc = unsafe { f(registers.as_mut_ptr(), mem) };
+            } else {
+                for inst in block.ops.iter() {
+                    interp::execute(*inst, mem, registers, pc);
+                }
+                if let Some(jit) = self.jit.as_mut() && block.hits < jit::HOT {
+                    block.hits += 1;
+                    if block.hits == jit::HOT {
+                        block.native = jit.compile(block.start, &block.insts);
+                    }
+                }
+            }
+            if STALE.load(Ordering::Relaxed) {
+                self.flush();
+                *guest_pc = *pc;
+                return Stop::Flushed;
+            }
+            if self.retired == self.max_insns || limits::expired() {
+                *guest_pc = *pc;
+                return if limits::expired() { Stop::Timeout } else { Stop::InsnLimit };
+            }
+            let links = block.links;
+            b = if links[0].0 == *pc {
+                links[0].1
+            } else if links[1].0 == *pc {
+                links[1].1
+            } else {
+                let n = match self.index.get(pc) {
+                    Some(&n) => n,
+                    None => self.translate(*pc, mem, registers),
+                };
+                // newest link first, the older one moves down
+                let block = &mut self.blocks[b];
+                block.links = [(*pc, n), block.links[0]];
+                n
+            };
+        }
+    }
+}
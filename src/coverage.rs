@@ -0,0 +1,123 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Guest code coverage (--drcov, --lcov). The translation cache reports each
+// block as it translates it, which is right before the block first runs, so
+// collecting costs nothing once the code is translated. A block the guest
+// crashes or is stopped in the middle of counts as run in full.
+//
+// drcov is the basic block format of DynamoRIO, read by Lighthouse and
+// bncov. lcov line coverage maps the blocks back to source lines through
+// the DWARF line table, so it needs a guest built with debug info.
+
+use std::collections::BTreeMap;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+
+use crate::dwarf;
+use crate::utils::ConvertibleError;
+
+pub(crate) struct Config {
+    pub drcov: Option<PathBuf>,
+    pub lcov: Option<PathBuf>,
+    /// Path of the guest executable
+    pub exe: String,
+    /// Where the executable is loaded
+    pub base: u64,
+    pub end: u64,
+    /// Executable address ranges, whose lines lcov reports on
+    pub code: Vec<(u64, u64)>,
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Blocks run: start address and length in bytes
+static BLOCKS: Mutex<Vec<(u64, u64)>> = Mutex::new(Vec::new());
+
+pub(crate) fn init(config: Config) {
+    if config.lcov.is_some() && !dwarf::available() {
+        eprintln!("riscv-um: warning: the guest has no debug info, lcov output will be empty");
+    }
+    let _ = CONFIG.set(config);
+}
+
+/// Note a block the guest is about to run.
+pub(crate) fn record(start: u64, len: u64) {
+    if CONFIG.get().is_some() {
+        BLOCKS.lock().unwrap_or_else(|e| e.into_inner()).push((start, len));
+    }
+}
+
+/// Each block once, by address. The cache translates a block again after a
+/// flush; keep the longest version.
+fn blocks() -> Vec<(u64, u64)> {
+    let mut unique = BTreeMap::new();
+    for &(start, len) in BLOCKS.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        let l = unique.entry(start).or_insert(0);
+        *l = len.max(*l);
+    }
+    unique.into_iter().collect()
+}
+
+fn write_drcov(path: &PathBuf, config: &Config, blocks: &[(u64, u64)]) -> std::io::Result<()> {
+    let mut out = BufWriter::new(std::fs::File::create(path)?);
+    let inside: Vec<_> = blocks.iter().filter(|b| b.0 >= config.base && b.0 < config.end).collect();
+    writeln!(out, "DRCOV VERSION: 2")?;
+    writeln!(out, "DRCOV FLAVOR: riscv-um")?;
+    writeln!(out, "Module Table: version 2, count 1")?;
+    writeln!(out, "Columns: id, base, end, entry, checksum, timestamp, path")?;
+    writeln!(out, " 0, {:#018x}, {:#018x}, {:#018x}, 0x00000000, 0x00000000, {}", config.base, config.end, 0, config.exe)?;
+    writeln!(out, "BB Table: {} bbs", inside.len())?;
+    for &&(start, len) in inside.iter() {
+        // struct { u32 start; u16 size; u16 id; }, start relative to the module
+        out.write_all(&((start - config.base) as u32).to_le_bytes())?;
+        out.write_all(&(len as u16).to_le_bytes())?;
+        out.write_all(&0u16.to_le_bytes())?;
+    }
+    out.flush()
+}
+
+fn write_lcov(path: &PathBuf, config: &Config, blocks: &[(u64, u64)]) -> std::io::Result<()> {
+    let run = |addr: u64, len: u64| {
+        // blocks never cross a page, so only the last few starting before
+        // the end of the row can overlap it
+        let i = blocks.partition_point(|b| b.0 < addr + len);
+        blocks[..i].iter().rev().take_while(|b| b.0 + 0x1000 > addr).any(|b| b.0 + b.1 > addr)
+    };
+    // file -> line -> whether any of its instructions ran
+    let mut files: BTreeMap<String, BTreeMap<u32, bool>> = BTreeMap::new();
+    for &(low, high) in config.code.iter() {
+        for (addr, len, file, line) in dwarf::line_table(low, high) {
+            let hit = files.entry(file).or_default().entry(line).or_default();
+            *hit |= run(addr, len);
+        }
+    }
+    let mut out = BufWriter::new(std::fs::File::create(path)?);
+    writeln!(out, "TN:")?;
+    for (file, lines) in files.iter() {
+        writeln!(out, "SF:{}", file)?;
+        for (line, hit) in lines.iter() {
+            writeln!(out, "DA:{},{}", line, *hit as u8)?;
+        }
+        writeln!(out, "LF:{}", lines.len())?;
+        writeln!(out, "LH:{}", lines.values().filter(|h| **h).count())?;
+        writeln!(out, "end_of_record")?;
+    }
+    out.flush()
+}
+
+/// Write the coverage files asked for, as the guest exits.
+pub(crate) fn write() {
+    let Some(config) = CONFIG.get() else {
+        return;
+    };
+    let blocks = blocks();
+    if let Some(path) = &config.drcov {
+        write_drcov(path, config, &blocks).e("Unable to write drcov file");
+    }
+    if let Some(path) = &config.lcov {
+        write_lcov(path, config, &blocks).e("Unable to write lcov file");
+    }
+}
@@ -0,0 +1,178 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Crash reports. When the guest does something it cannot go on from (an
+// instruction we cannot decode, an ebreak with no debugger attached, a jump
+// or access outside guest memory) print where it was and the state it was
+// in, then exit the way a process killed by the matching signal would, with
+// status 128+SIG. Scripts can tell a guest crash from an emulator failure.
+//
+// Wild loads and stores are caught as host SIGSEGV/SIGBUS: guest memory sits
+// in a reserved window that faults outside program memory and the stack,
+// and every other guest address is translated to a page of it that is never
+// mapped (see offset). Whatever is
+// running records the pc of its block (or single instruction) in CURRENT,
+// and the faulting instruction is found by matching the host fault address
+// against the accesses of that block. A fault in guest memory that no
+// access explains (one made by syscall emulation, or by a Cranelift block
+// whose registers have not been written back) is still reported, with the
+// access unknown; any other host fault is an emulator bug and is left to
+// kill us as usual. With the Cranelift backend guest registers may still be
+// held in host registers, so the dump can be out of date.
+
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crate::adt;
+use crate::decode;
+use crate::disasm::disasm;
+use crate::dwarf;
+use crate::interp;
+use crate::mapped;
+use crate::GUEST_WINDOW;
+use crate::symbols;
+use crate::trace;
+
+/// Start of the block, or the instruction, being executed
+pub(crate) static CURRENT: AtomicU64 = AtomicU64::new(0);
+
+static MEM: AtomicPtr<libc::c_void> = AtomicPtr::new(std::ptr::null_mut());
+static REGISTERS: AtomicPtr<[u64; 32]> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Deepest backtrace printed
+const MAX_FRAMES: usize = 32;
+
+fn at(addr: u64) -> String {
+    symbols::get().map_or_else(|| format!("{:#x}", addr), |s| s.format(addr))
+}
+
+fn read64(mem: *mut libc::c_void, addr: u64) -> u64 {
+    unsafe { *(adt(addr, mem) as *const u64) }
+}
+
+/// Return addresses found by following the frame pointer (s0): a frame
+/// keeps the caller's ra at fp-8 and the caller's fp at fp-16.
+fn backtrace(mem: *mut libc::c_void, registers: &[u64; 32]) -> Vec<u64> {
+    let mut frames = Vec::new();
+    let mut fp = registers[8];
+    while frames.len() < MAX_FRAMES {
+        // frames live on the stack
+        if fp & (1 << 38) == 0 || fp < 16 || !mapped(fp - 16, 16) {
+            break;
+        }
+        let ra = read64(mem, fp - 8);
+        let prev = read64(mem, fp - 16);
+        if ra == 0 {
+            break;
+        }
+        frames.push(ra);
+        if prev <= fp {
+            break;
+        }
+        fp = prev;
+    }
+    frames
+}
+
+/// Print the report for a guest stopped at pc and exit with 128+sig.
+pub(crate) fn report(sig: i32, why: &str, mem: *mut libc::c_void, registers: &[u64; 32], pc: u64) -> ! {
+    let name = match sig {
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGBUS => "SIGBUS",
+        _ => "SIGSEGV",
+    };
+    trace::flush();
+    eprintln!("riscv-um: guest crashed: {} ({})", why, name);
+    eprintln!();
+    interp::dump_registers(registers, pc);
+    eprintln!();
+    eprintln!("code:");
+    for addr in (pc.saturating_sub(16)..pc.saturating_add(12)).step_by(4) {
+        let marker = if addr == pc { "=>" } else { "  " };
+        if mapped(addr, 4) {
+            let isn = unsafe { *(adt(addr, mem) as *const u32) };
+            eprintln!("{} {:<28} {:08x}  {}", marker, at(addr), isn, disasm(isn, addr));
+        }
+    }
+    let sp = registers[2];
+    eprintln!();
+    eprintln!("stack:");
+    for addr in (sp..sp.saturating_add(64)).step_by(16) {
+        if mapped(addr, 16) {
+            eprintln!("   {:#x}  {:016x} {:016x}", addr, read64(mem, addr), read64(mem, addr + 8));
+        }
+    }
+    eprintln!();
+    eprintln!("backtrace:");
+    for (i, addr) in std::iter::once(pc).chain(backtrace(mem, registers)).enumerate() {
+        // a return address is just past the call it returns from
+        let source = dwarf::frames(if i == 0 { addr } else { addr - 1 });
+        match source.split_first() {
+            Some((inner, outer)) => {
+                let function = inner.function.as_ref().map(|f| format!(" in {}", f)).unwrap_or_default();
+                let location = inner.location.as_ref().map(|l| format!(" at {}", l)).unwrap_or_default();
+                eprintln!("   #{:<2} {}{}{}", i, at(addr), function, location);
+                for frame in outer {
+                    eprintln!("       inlined into {}", frame);
+                }
+            }
+            None => eprintln!("   #{:<2} {}", i, at(addr)),
+        }
+    }
+    crate::exit(128 + sig);
+}
+
+/// The guest instruction whose access hit host address `host`, searching
+/// the block starting at start: its pc and the guest address it accessed.
+fn faulting_access(mem: *mut libc::c_void, registers: &[u64; 32], start: u64, host: u64) -> Option<(u64, u64)> {
+    let mut pc = start;
+    while mapped(pc, 4) {
+        let inst = decode::decode(unsafe { *(adt(pc, mem) as *const u32) });
+        if let Some((addr, len, _)) = interp::access(inst, registers) {
+            let base = adt(addr, mem) as u64;
+            if (base..base + len).contains(&host) {
+                return Some((pc, addr));
+            }
+        }
+        pc += 4;
+        if inst.ends_block() || pc & 0xfff == 0 {
+            break;
+        }
+    }
+    None
+}
+
+extern "C" fn on_fault(sig: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    let mem = MEM.load(Ordering::Relaxed);
+    let registers = REGISTERS.load(Ordering::Relaxed);
+    let host = unsafe { (*info).si_addr() } as u64;
+    if !registers.is_null() {
+        let registers = unsafe { &*registers };
+        let start = CURRENT.load(Ordering::Relaxed);
+        if !mapped(start, 4) {
+            report(sig, &format!("jump to unmapped address {:#x}", start), mem, registers, start);
+        }
+        if let Some((pc, addr)) = faulting_access(mem, registers, start, host) {
+            report(sig, &format!("access to unmapped address {:#x}", addr), mem, registers, pc);
+        }
+        if host.wrapping_sub(mem as u64) < GUEST_WINDOW as u64 {
+            report(sig, "access to unmapped memory at an unknown address", mem, registers, start);
+        }
+    }
+    // not the guest's doing: let the signal take us down
+    unsafe { libc::signal(sig, libc::SIG_DFL) };
+}
+
+/// Catch the guest's wild memory accesses from now on.
+pub(crate) fn install(mem: *mut libc::c_void, registers: &mut [u64; 32]) {
+    MEM.store(mem, Ordering::Relaxed);
+    REGISTERS.store(registers, Ordering::Relaxed);
+    unsafe {
+        let mut sa: libc::sigaction = std::mem::zeroed();
+        sa.sa_sigaction = on_fault as *const () as libc::sighandler_t;
+        sa.sa_flags = libc::SA_SIGINFO;
+        libc::sigaction(libc::SIGSEGV, &sa, std::ptr::null_mut());
+        libc::sigaction(libc::SIGBUS, &sa, std::ptr::null_mut());
+    }
+}
@@ -0,0 +1,312 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Interactive debugger (--debug). A small command prompt in the emulator
+// itself, for when attaching gdb is more than is needed. Like the gdb stub it
+// runs the guest on the plain interpreter, one instruction at a time.
+//
+// Commands are read from the terminal when there is one, so the guest keeps
+// its stdin; everything the debugger prints goes to stderr.
+
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use crate::adt;
+use crate::decode::Instruction;
+use crate::disasm::disasm;
+use crate::dwarf;
+use crate::interp;
+use crate::interp::REG_NAMES;
+use crate::mapped;
+use crate::symbols::Symbols;
+use crate::syscall;
+
+const HELP: &str = "\
+step|s [N]            execute N instructions (default 1)
+next|n [N]            like step, but run over calls
+continue|c            run until a breakpoint, ebreak or ^C
+break|b [ADDR]        set a breakpoint, or list them
+delete|d [NUM]        delete a breakpoint, or all of them
+regs|r [REG]          show all registers, or one
+x ADDR [N]            show N doublewords of memory (default 1)
+disas|l [ADDR] [N]    disassemble N instructions (default: around pc)
+set REG VALUE         change a register or pc
+quit|q                exit
+ADDR and VALUE may be numbers, symbols, registers or pc.
+An empty line repeats the last command.";
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+struct Debugger<'a> {
+    symbols: &'a Symbols,
+    mem: *mut libc::c_void,
+    /// (number, address), in the order they were set
+    breakpoints: Vec<(u32, u64)>,
+    next_breakpoint: u32,
+}
+
+fn word(mem: *mut libc::c_void, addr: u64) -> Option<u32> {
+    mapped(addr, 4).then(|| unsafe { *(adt(addr, mem) as *const u32) })
+}
+
+fn register(name: &str) -> Option<usize> {
+    if let Some(n) = name.strip_prefix('x').and_then(|n| n.parse::<usize>().ok()) {
+        return (n < 32).then_some(n);
+    }
+    match name {
+        "fp" => Some(8),
+        _ => REG_NAMES.iter().position(|r| *r == name),
+    }
+}
+
+impl Debugger<'_> {
+    /// A number, symbol, register or pc
+    fn value(&self, s: &str, registers: &[u64; 32], pc: u64) -> Option<u64> {
+        if s == "pc" {
+            return Some(pc);
+        }
+        if let Some(n) = register(s) {
+            return Some(registers[n]);
+        }
+        if let Some(addr) = self.symbols.lookup(s) {
+            return Some(addr);
+        }
+        match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => s.parse::<i64>().ok().map(|v| v as u64),
+        }
+    }
+
+    fn show_insn(&self, addr: u64, current: bool) {
+        let marker = if current { "=>" } else { "  " };
+        match word(self.mem, addr) {
+            Some(w) => eprintln!("{} {}:\t{:08x}\t{}", marker, self.symbols.format(addr), w, disasm(w, addr)),
+            None => eprintln!("{} {}:\t<unmapped>", marker, self.symbols.format(addr)),
+        }
+    }
+
+    fn breakpoint_at(&self, addr: u64) -> Option<u32> {
+        self.breakpoints.iter().find(|b| b.1 == addr).map(|b| b.0)
+    }
+
+    /// Execute the instruction at pc. Returns why the guest cannot go on, if
+    /// it cannot.
+    fn execute(&self, registers: &mut [u64; 32], pc: &mut u64) -> Option<String> {
+        if !mapped(*pc, 4) {
+            return Some(format!("pc {:#x} is not in guest memory", *pc));
+        }
+        let inst = interp::fetch(self.mem, *pc);
+        match inst {
+            Instruction::Ebreak => return Some("ebreak".into()),
+            Instruction::Ecall if syscall::is_exit(registers[17]) => {
+                eprintln!("[guest exited with status {}]", registers[10] as i32);
+            }
+            _ => {}
+        }
+        interp::execute(inst, self.mem, registers, pc);
+        None
+    }
+
+    /// Run until `done` says to stop, a breakpoint is reached or ^C is hit.
+    fn run_until(&self, registers: &mut [u64; 32], pc: &mut u64, done: impl Fn(&[u64; 32], u64) -> bool) -> Option<String> {
+        INTERRUPTED.store(false, Ordering::Relaxed);
+        loop {
+            if let Some(why) = self.execute(registers, pc) {
+                return Some(why);
+            }
+            if done(registers, *pc) {
+                return None;
+            }
+            if let Some(n) = self.breakpoint_at(*pc) {
+                return Some(format!("Breakpoint {}", n));
+            }
+            if INTERRUPTED.load(Ordering::Relaxed) {
+                return Some("Interrupted".into());
+            }
+        }
+    }
+
+    /// One instruction, or a whole call when over_calls is set
+    fn step(&self, registers: &mut [u64; 32], pc: &mut u64, over_calls: bool) -> Option<String> {
+        // resuming from an ebreak goes past it
+        if word(self.mem, *pc) == Some(0x00100073) {
+            *pc += 4;
+            return None;
+        }
+        let call = mapped(*pc, 4)
+            && matches!(interp::fetch(self.mem, *pc), Instruction::Jal { rd: 1.., .. } | Instruction::Jalr { rd: 1.., .. });
+        if !(over_calls && call) {
+            return self.execute(registers, pc);
+        }
+        let ret = *pc + 4;
+        let sp = registers[2];
+        // a recursive call can come back to ret deeper in the stack
+        self.run_until(registers, pc, |r, pc| pc == ret && r[2] >= sp)
+    }
+
+    /// Handle one command line; false when the user is done.
+    fn command(&mut self, line: &str, registers: &mut [u64; 32], pc: &mut u64) -> bool {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&cmd, args)) = words.split_first() else {
+            return true;
+        };
+        let count = |i: usize, default: u64| match args.get(i) {
+            Some(a) => self.value(a, registers, *pc),
+            None => Some(default),
+        };
+        let stopped = match cmd {
+            "s" | "step" | "n" | "next" => {
+                let Some(n) = count(0, 1) else {
+                    eprintln!("Bad count");
+                    return true;
+                };
+                let mut why = None;
+                for _ in 0..n {
+                    why = self.step(registers, pc, matches!(cmd, "n" | "next"));
+                    if why.is_some() {
+                        break;
+                    }
+                }
+                why
+            }
+            "c" | "continue" => match self.step(registers, pc, false) {
+                None => self.run_until(registers, pc, |_, _| false),
+                why => why,
+            },
+            "b" | "break" => {
+                match args.first() {
+                    None => {
+                        for (n, addr) in &self.breakpoints {
+                            eprintln!("{}\t{}", n, self.symbols.format(*addr));
+                        }
+                    }
+                    Some(a) => match self.value(a, registers, *pc) {
+                        Some(addr) => {
+                            self.next_breakpoint += 1;
+                            self.breakpoints.push((self.next_breakpoint, addr));
+                            eprintln!("Breakpoint {} at {}", self.next_breakpoint, self.symbols.format(addr));
+                        }
+                        None => eprintln!("No symbol or address \"{}\"", a),
+                    },
+                }
+                return true;
+            }
+            "d" | "delete" => {
+                match args.first().map(|a| a.parse::<u32>()) {
+                    None => self.breakpoints.clear(),
+                    Some(Ok(n)) if self.breakpoints.iter().any(|b| b.0 == n) => self.breakpoints.retain(|b| b.0 != n),
+                    Some(_) => eprintln!("No breakpoint {}", args[0]),
+                }
+                return true;
+            }
+            "r" | "regs" => {
+                match args.first() {
+                    None => interp::dump_registers(registers, *pc),
+                    Some(&"pc") => eprintln!("pc   {}", self.symbols.format(*pc)),
+                    Some(a) => match register(a) {
+                        Some(n) => eprintln!("{:<4} {:#018x} {}", REG_NAMES[n], registers[n], registers[n] as i64),
+                        None => eprintln!("No register \"{}\"", a),
+                    },
+                }
+                return true;
+            }
+            "x" => {
+                let (Some(addr), Some(n)) = (args.first().and_then(|a| self.value(a, registers, *pc)), count(1, 1)) else {
+                    eprintln!("Usage: x ADDR [N]");
+                    return true;
+                };
+                for i in 0..n {
+                    let addr = addr.wrapping_add(i * 8);
+                    if !mapped(addr, 8) {
+                        eprintln!("{:#x}: <unmapped>", addr);
+                        break;
+                    }
+                    eprintln!("{}:\t{:#018x}", self.symbols.format(addr), unsafe { *(adt(addr, self.mem) as *const u64) });
+                }
+                return true;
+            }
+            "l" | "disas" => {
+                let (start, n) = match args.first() {
+                    None => (pc.saturating_sub(8), 8),
+                    Some(a) => match (self.value(a, registers, *pc), count(1, 8)) {
+                        (Some(addr), Some(n)) => (addr, n),
+                        _ => {
+                            eprintln!("Usage: disas [ADDR] [N]");
+                            return true;
+                        }
+                    },
+                };
+                for i in 0..n {
+                    self.show_insn(start.wrapping_add(i * 4), start.wrapping_add(i * 4) == *pc);
+                }
+                return true;
+            }
+            "set" => {
+                let parsed = match args {
+                    [r, v] => Some((*r, self.value(v, registers, *pc))),
+                    _ => None,
+                };
+                match parsed {
+                    Some(("pc", Some(v))) => *pc = v,
+                    Some((r, Some(v))) => match register(r) {
+                        Some(0) => eprintln!("zero is always zero"),
+                        Some(n) => registers[n] = v,
+                        None => eprintln!("No register \"{}\"", r),
+                    },
+                    _ => eprintln!("Usage: set REG VALUE"),
+                }
+                return true;
+            }
+            "q" | "quit" => return false,
+            "h" | "help" => {
+                eprintln!("{}", HELP);
+                return true;
+            }
+            _ => {
+                eprintln!("Unknown command \"{}\", try \"help\"", cmd);
+                return true;
+            }
+        };
+        if let Some(why) = stopped {
+            eprintln!("{}", why);
+        }
+        if let Some(frame) = dwarf::frames(*pc).first() {
+            eprintln!("{}", frame);
+        }
+        self.show_insn(*pc, true);
+        true
+    }
+}
+
+/// Give the user a prompt to drive the guest from. Never returns.
+pub(crate) fn run(symbols: &Symbols, mem: *mut libc::c_void, registers: &mut [u64; 32], pc: &mut u64) -> ! {
+    let input: Box<dyn BufRead> = match std::fs::File::open("/dev/tty") {
+        Ok(tty) => Box::new(BufReader::new(tty)),
+        Err(_) => Box::new(std::io::stdin().lock()),
+    };
+    unsafe { libc::signal(libc::SIGINT, on_sigint as *const () as libc::sighandler_t) };
+    let mut d = Debugger { symbols, mem, breakpoints: Vec::new(), next_breakpoint: 0 };
+    d.show_insn(*pc, true);
+    let mut last = String::new();
+    let mut lines = input.lines();
+    loop {
+        eprint!("(rvdb) ");
+        let _ = std::io::stderr().flush();
+        let Some(Ok(line)) = lines.next() else {
+            crate::exit(0);
+        };
+        if !line.trim().is_empty() {
+            last = line;
+        }
+        let line = last.clone();
+        if !d.command(&line, registers, pc) {
+            crate::exit(0);
+        }
+    }
+}
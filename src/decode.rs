@@ -0,0 +1,301 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Instruction decoding. Each 32-bit word is turned into an Instruction with
+// its register numbers and immediates already extracted and sign-extended,
+// so the hot path never looks at raw encoding bits. Decoded instructions are
+// cached by the translation cache in block.rs.
+
+use crate::utils;
+
+/// A decoded instruction. Immediates are kept as i32 to keep this at eight
+/// bytes and sign-extended again when executed.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Instruction {
+    Lw { rd: u8, rs1: u8, imm: i32 },
+    Ld { rd: u8, rs1: u8, imm: i32 },
+    Addi { rd: u8, rs1: u8, imm: i32 },
+    Slli { rd: u8, rs1: u8, shamt: u8 },
+    Srli { rd: u8, rs1: u8, shamt: u8 },
+    Andi { rd: u8, rs1: u8, imm: i32 },
+    Auipc { rd: u8, imm: i32 },
+    Addiw { rd: u8, rs1: u8, imm: i32 },
+    Slliw { rd: u8, rs1: u8, shamt: u8 },
+    Sb { rs1: u8, rs2: u8, imm: i32 },
+    Sw { rs1: u8, rs2: u8, imm: i32 },
+    Sd { rs1: u8, rs2: u8, imm: i32 },
+    Add { rd: u8, rs1: u8, rs2: u8 },
+    Sub { rd: u8, rs1: u8, rs2: u8 },
+    Sll { rd: u8, rs1: u8, rs2: u8 },
+    Lui { rd: u8, imm: i32 },
+    Addw { rd: u8, rs1: u8, rs2: u8 },
+    Subw { rd: u8, rs1: u8, rs2: u8 },
+    Beq { rs1: u8, rs2: u8, imm: i32 },
+    Bne { rs1: u8, rs2: u8, imm: i32 },
+    Blt { rs1: u8, rs2: u8, imm: i32 },
+    Jalr { rd: u8, rs1: u8, imm: i32 },
+    Jal { rd: u8, imm: i32 },
+    /// lui + addi/addiw building a 32-bit constant
+    LoadImm { rd: u8, value: i32 },
+    /// auipc + jalr through rt, to pc + offset
+    Call { rd: u8, rt: u8, offset: i32 },
+    /// auipc + ld through rt from pc + offset, as used for GOT loads
+    AuipcLd { rd: u8, rt: u8, offset: i32 },
+    /// slli + srli by the same amount, clearing the top bits
+    Zext { rd: u8, rs1: u8, shamt: u8 },
+    /// li rt, k followed by a branch comparing rs1 against rt
+    BranchImm { cond: Cond, rs1: u8, rt: u8, k: i16, imm: i16 },
+    Fence,
+    FenceI,
+    Ecall,
+    Ebreak,
+    /// CSR accesses are accepted and ignored for now
+    Csr,
+    /// Anything we cannot execute yet, kept as the raw word
+    Unimplemented(u32),
+}
+
+/// Comparison of a fused compare-and-branch, with the constant on the right
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Cond {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+}
+
+// the dispatch loop copies these around, so keep them small
+const _: () = assert!(std::mem::size_of::<Instruction>() == 8);
+
+#[inline(always)]
+fn rd(isn: u32) -> u8 {
+    ((isn & 0x0000_0f80) >> 7) as u8
+}
+
+#[inline(always)]
+fn rs1(isn: u32) -> u8 {
+    ((isn & 0x000f_8000) >> 15) as u8
+}
+
+#[inline(always)]
+fn rs2(isn: u32) -> u8 {
+    ((isn & 0x01f0_0000) >> 20) as u8
+}
+
+#[inline(always)]
+fn funct3(isn: u32) -> u32 {
+    (isn & 0x0000_7000) >> 12
+}
+
+#[inline(always)]
+fn funct7(isn: u32) -> u32 {
+    isn >> 25
+}
+
+#[inline(always)]
+fn i_imm(isn: u32) -> i32 {
+    utils::sign_extend_12(((isn & 0xfff0_0000) >> 20) as u64) as i32
+}
+
+#[inline(always)]
+fn s_imm(isn: u32) -> i32 {
+    utils::sign_extend_12((((isn & 0xfe00_0000) >> 20) | ((isn & 0x0000_0f80) >> 7)) as u64) as i32
+}
+
+#[inline(always)]
+fn b_imm(isn: u32) -> i32 {
+    utils::sign_extend_13(
+        (((isn & 0x8000_0000) >> 19)
+            | ((isn & 0x7e00_0000) >> 20)
+            | ((isn & 0x0000_0f00) >> 7)
+            | ((isn & 0x0000_0080) << 4)) as u64,
+    ) as i32
+}
+
+#[inline(always)]
+fn u_imm(isn: u32) -> i32 {
+    (isn & 0xffff_f000) as i32
+}
+
+#[inline(always)]
+fn j_imm(isn: u32) -> i32 {
+    utils::sign_extend_21(
+        (((isn & 0x8000_0000) >> 11)
+            | ((isn & 0x7fe0_0000) >> 20)
+            | ((isn & 0x0010_0000) >> 9)
+            | (isn & 0x000f_f000)) as u64,
+    ) as i32
+}
+
+#[inline]
+pub(crate) fn decode(isn: u32) -> Instruction {
+    use Instruction::*;
+    let (rd, rs1, rs2) = (rd(isn), rs1(isn), rs2(isn));
+    match isn & 0x7f {
+        // MISC-MEM
+        0x0f => match funct3(isn) {
+            0 => Fence,
+            1 => FenceI,
+            _ => Unimplemented(isn),
+        },
+        // LOAD
+        0x03 => match funct3(isn) {
+            2 => Lw { rd, rs1, imm: i_imm(isn) },
+            3 => Ld { rd, rs1, imm: i_imm(isn) },
+            _ => Unimplemented(isn),
+        },
+        // OP-IMM
+        0x13 => match funct3(isn) {
+            0 => Addi { rd, rs1, imm: i_imm(isn) },
+            1 if isn >> 26 == 0 => Slli { rd, rs1, shamt: ((isn >> 20) & 0x3f) as u8 },
+            5 if isn >> 26 == 0 => Srli { rd, rs1, shamt: ((isn >> 20) & 0x3f) as u8 },
+            7 => Andi { rd, rs1, imm: i_imm(isn) },
+            _ => Unimplemented(isn),
+        },
+        0x17 => Auipc { rd, imm: u_imm(isn) },
+        // OP-IMM-32
+        0x1b => match funct3(isn) {
+            0 => Addiw { rd, rs1, imm: i_imm(isn) },
+            1 if funct7(isn) == 0 => Slliw { rd, rs1, shamt: rs2 },
+            _ => Unimplemented(isn),
+        },
+        // STORE
+        0x23 => match funct3(isn) {
+            0 => Sb { rs1, rs2, imm: s_imm(isn) },
+            2 => Sw { rs1, rs2, imm: s_imm(isn) },
+            3 => Sd { rs1, rs2, imm: s_imm(isn) },
+            _ => Unimplemented(isn),
+        },
+        // OP
+        0x33 => match (funct7(isn), funct3(isn)) {
+            (0x00, 0) => Add { rd, rs1, rs2 },
+            (0x20, 0) => Sub { rd, rs1, rs2 },
+            (0x00, 1) => Sll { rd, rs1, rs2 },
+            _ => Unimplemented(isn),
+        },
+        0x37 => Lui { rd, imm: u_imm(isn) },
+        // OP-32
+        0x3b => match (funct7(isn), funct3(isn)) {
+            (0x00, 0) => Addw { rd, rs1, rs2 },
+            (0x20, 0) => Subw { rd, rs1, rs2 },
+            _ => Unimplemented(isn),
+        },
+        // BRANCH
+        0x63 => match funct3(isn) {
+            0 => Beq { rs1, rs2, imm: b_imm(isn) },
+            1 => Bne { rs1, rs2, imm: b_imm(isn) },
+            4 => Blt { rs1, rs2, imm: b_imm(isn) },
+            _ => Unimplemented(isn),
+        },
+        0x67 if funct3(isn) == 0 => Jalr { rd, rs1, imm: i_imm(isn) },
+        0x6f => Jal { rd, imm: j_imm(isn) },
+        // SYSTEM
+        0x73 => match (funct3(isn), isn >> 20) {
+            (0, 0) if rd == 0 && rs1 == 0 => Ecall,
+            (0, 1) if rd == 0 && rs1 == 0 => Ebreak,
+            (0, _) => Unimplemented(isn),
+            _ => Csr,
+        },
+        _ => Unimplemented(isn),
+    }
+}
+
+impl Instruction {
+    /// Whether a translated block has to stop after this instruction.
+    pub(crate) fn ends_block(self) -> bool {
+        use Instruction::*;
+        matches!(
+            self,
+            Beq { .. }
+                | Bne { .. }
+                | Blt { .. }
+                | Jal { .. }
+                | Jalr { .. }
+                | Call { .. }
+                | BranchImm { .. }
+                | FenceI
+                | Ecall
+                | Ebreak
+                | Unimplemented(_)
+        )
+    }
+
+    /// The register an unfused instruction writes, if any. An ecall may
+    /// write a0.
+    pub(crate) fn rd(self) -> Option<u8> {
+        use Instruction::*;
+        let rd = match self {
+            Lw { rd, .. } | Ld { rd, .. } | Addi { rd, .. } | Slli { rd, .. } | Srli { rd, .. } | Andi { rd, .. } => rd,
+            Auipc { rd, .. } | Addiw { rd, .. } | Slliw { rd, .. } | Add { rd, .. } | Sub { rd, .. } | Sll { rd, .. } => rd,
+            Lui { rd, .. } | Addw { rd, .. } | Subw { rd, .. } | Jalr { rd, .. } | Jal { rd, .. } => rd,
+            Ecall => 10,
+            _ => return None,
+        };
+        (rd != 0).then_some(rd)
+    }
+}
+
+/// The auipc part of a fused pc-relative offset: lo is a sign-extended
+/// 12-bit immediate, so the split is unique.
+pub(crate) fn hi_part(offset: i32) -> i64 {
+    (offset as i64 + 0x800) & !0xfff
+}
+
+/// Fuse one pair of instructions into a single operation, if it is an idiom
+/// we know.
+fn fuse_pair(a: Instruction, b: Instruction) -> Option<Instruction> {
+    use Instruction::*;
+    Some(match (a, b) {
+        (Lui { rd, imm: hi }, Addi { rd: rd2, rs1, imm: lo }) if rd != 0 && rd2 == rd && rs1 == rd => {
+            LoadImm { rd, value: i32::try_from(hi as i64 + lo as i64).ok()? }
+        }
+        (Lui { rd, imm: hi }, Addiw { rd: rd2, rs1, imm: lo }) if rd != 0 && rd2 == rd && rs1 == rd => {
+            LoadImm { rd, value: hi.wrapping_add(lo) }
+        }
+        (Auipc { rd: rt, imm: hi }, Jalr { rd, rs1, imm: lo }) if rt != 0 && rs1 == rt => {
+            Call { rd, rt, offset: i32::try_from(hi as i64 + lo as i64).ok()? }
+        }
+        (Auipc { rd: rt, imm: hi }, Ld { rd, rs1, imm: lo }) if rt != 0 && rs1 == rt => {
+            AuipcLd { rd, rt, offset: i32::try_from(hi as i64 + lo as i64).ok()? }
+        }
+        (Slli { rd, rs1, shamt }, Srli { rd: rd2, rs1: rs2, shamt: shamt2 })
+            if rd2 == rd && rs2 == rd && shamt2 == shamt =>
+        {
+            Zext { rd, rs1, shamt }
+        }
+        (Addi { rd: rt, rs1: 0, imm: k }, br) if rt != 0 => {
+            let (cond, rs1, rs2, imm) = match br {
+                Beq { rs1, rs2, imm } => (Cond::Eq, rs1, rs2, imm),
+                Bne { rs1, rs2, imm } => (Cond::Ne, rs1, rs2, imm),
+                Blt { rs1, rs2, imm } => (Cond::Lt, rs1, rs2, imm),
+                _ => return None,
+            };
+            // put the constant on the right
+            let (cond, rs1) = match (rs1 == rt, rs2 == rt) {
+                (false, true) => (cond, rs1),
+                (true, false) => (if let Cond::Lt = cond { Cond::Gt } else { cond }, rs2),
+                _ => return None,
+            };
+            BranchImm { cond, rs1, rt, k: k as i16, imm: imm as i16 }
+        }
+        _ => return None,
+    })
+}
+
+/// Replace known two-instruction idioms in a block with fused operations,
+/// in place.
+pub(crate) fn fuse(insts: &mut Vec<Instruction>) {
+    let (mut i, mut out) = (0, 0);
+    while i < insts.len() {
+        if i + 1 < insts.len()
+            && let Some(f) = fuse_pair(insts[i], insts[i + 1])
+        {
+            insts[out] = f;
+            i += 2;
+        } else {
+            insts[out] = insts[i];
+            i += 1;
+        }
+        out += 1;
+    }
+    insts.truncate(out);
+}
@@ -0,0 +1,144 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Disassembly of the instructions the decoder knows, in the style of
+// objdump, with branch and jump targets shown as absolute addresses. Backs
+// the trace, the debugger and `riscv-um disasm`, which lists the executable
+// sections of an ELF file.
+
+use std::io::BufWriter;
+use std::io::Write;
+
+use elf::ElfBytes;
+use elf::endian::LittleEndian;
+
+use crate::decode;
+use crate::decode::Instruction;
+use crate::interp::REG_NAMES;
+use crate::symbols::Symbols;
+use crate::utils::ConvertibleError;
+
+fn r(n: u8) -> &'static str {
+    REG_NAMES[n as usize]
+}
+
+/// The instruction word isn found at pc, as text.
+pub(crate) fn disasm(isn: u32, pc: u64) -> String {
+    use Instruction::*;
+    let target = |imm: i32| pc.wrapping_add(imm as i64 as u64);
+    match decode::decode(isn) {
+        Lw { rd, rs1, imm } => format!("lw {}, {}({})", r(rd), imm, r(rs1)),
+        Ld { rd, rs1, imm } => format!("ld {}, {}({})", r(rd), imm, r(rs1)),
+        Addi { rd: 0, rs1: 0, imm: 0 } => "nop".into(),
+        Addi { rd, rs1: 0, imm } => format!("li {}, {}", r(rd), imm),
+        Addi { rd, rs1, imm: 0 } => format!("mv {}, {}", r(rd), r(rs1)),
+        Addi { rd, rs1, imm } => format!("addi {}, {}, {}", r(rd), r(rs1), imm),
+        Slli { rd, rs1, shamt } => format!("slli {}, {}, {}", r(rd), r(rs1), shamt),
+        Srli { rd, rs1, shamt } => format!("srli {}, {}, {}", r(rd), r(rs1), shamt),
+        Andi { rd, rs1, imm } => format!("andi {}, {}, {}", r(rd), r(rs1), imm),
+        Auipc { rd, imm } => format!("auipc {}, {:#x}", r(rd), (imm as u32) >> 12),
+        Addiw { rd, rs1, imm: 0 } => format!("sext.w {}, {}", r(rd), r(rs1)),
+        Addiw { rd, rs1, imm } => format!("addiw {}, {}, {}", r(rd), r(rs1), imm),
+        Slliw { rd, rs1, shamt } => format!("slliw {}, {}, {}", r(rd), r(rs1), shamt),
+        Sb { rs1, rs2, imm } => format!("sb {}, {}({})", r(rs2), imm, r(rs1)),
+        Sw { rs1, rs2, imm } => format!("sw {}, {}({})", r(rs2), imm, r(rs1)),
+        Sd { rs1, rs2, imm } => format!("sd {}, {}({})", r(rs2), imm, r(rs1)),
+        Add { rd, rs1, rs2 } => format!("add {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Sub { rd, rs1, rs2 } => format!("sub {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Sll { rd, rs1, rs2 } => format!("sll {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Lui { rd, imm } => format!("lui {}, {:#x}", r(rd), (imm as u32) >> 12),
+        Addw { rd, rs1, rs2 } => format!("addw {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Subw { rd, rs1, rs2 } => format!("subw {}, {}, {}", r(rd), r(rs1), r(rs2)),
+        Beq { rs1, rs2, imm } => format!("beq {}, {}, {:#x}", r(rs1), r(rs2), target(imm)),
+        Bne { rs1, rs2, imm } => format!("bne {}, {}, {:#x}", r(rs1), r(rs2), target(imm)),
+        Blt { rs1, rs2, imm } => format!("blt {}, {}, {:#x}", r(rs1), r(rs2), target(imm)),
+        Jalr { rd: 0, rs1: 1, imm: 0 } => "ret".into(),
+        Jalr { rd: 0, rs1, imm: 0 } => format!("jr {}", r(rs1)),
+        Jalr { rd, rs1, imm } => format!("jalr {}, {}({})", r(rd), imm, r(rs1)),
+        Jal { rd: 0, imm } => format!("j {:#x}", target(imm)),
+        Jal { rd, imm } => format!("jal {}, {:#x}", r(rd), target(imm)),
+        Fence => "fence".into(),
+        FenceI => "fence.i".into(),
+        Ecall => "ecall".into(),
+        Ebreak => "ebreak".into(),
+        Csr => csr(isn),
+        _ => unknown(isn),
+    }
+}
+
+/// An encoding the disassembler does not know, as the raw word
+fn unknown(isn: u32) -> String {
+    format!(".word {:#010x}", isn)
+}
+
+fn csr_name(csr: u32) -> String {
+    match csr {
+        0x001 => "fflags".into(),
+        0x002 => "frm".into(),
+        0x003 => "fcsr".into(),
+        0xc00 => "cycle".into(),
+        0xc01 => "time".into(),
+        0xc02 => "instret".into(),
+        _ => format!("{:#x}", csr),
+    }
+}
+
+/// The Zicsr instructions, which decode to Instruction::Csr
+fn csr(isn: u32) -> String {
+    let rd = ((isn >> 7) & 0x1f) as u8;
+    let src = ((isn >> 15) & 0x1f) as u8;
+    let csr = csr_name(isn >> 20);
+    let op = match (isn >> 12) & 7 {
+        1 => "csrrw",
+        2 => "csrrs",
+        3 => "csrrc",
+        5 => "csrrwi",
+        6 => "csrrsi",
+        7 => "csrrci",
+        // 4 is reserved, 0 is the other SYSTEM instructions
+        _ => return unknown(isn),
+    };
+    // the immediate forms take a 5-bit constant in place of rs1
+    let src = if op.ends_with('i') { src.to_string() } else { r(src).to_string() };
+    match (op, rd, src.as_str()) {
+        ("csrrs", _, "zero") => format!("csrr {}, {}", r(rd), csr),
+        (_, 0, _) => format!("{} {}, {}", op.replace("csrr", "csr"), csr, src),
+        _ => format!("{} {}, {}, {}", op, r(rd), csr, src),
+    }
+}
+
+/// Print the executable sections of an ELF file, labelled with its symbols.
+pub(crate) fn dump(elf_f: &ElfBytes<LittleEndian>) -> std::io::Result<()> {
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    let symbols = Symbols::load(elf_f);
+    let (shdrs, strtab) = elf_f.section_headers_with_strtab().e("Failed to get section headers");
+    let (Some(shdrs), Some(strtab)) = (shdrs, strtab) else {
+        return Ok(());
+    };
+    for shdr in shdrs.iter() {
+        if shdr.sh_type != elf::abi::SHT_PROGBITS || shdr.sh_flags & elf::abi::SHF_EXECINSTR as u64 == 0 {
+            continue;
+        }
+        let name = strtab.get(shdr.sh_name as usize).unwrap_or("?");
+        let (data, _) = elf_f.section_data(&shdr).e("Failed to get section data");
+        writeln!(out, "\nDisassembly of section {}:", name)?;
+        let mut off = 0;
+        while off < data.len() {
+            let addr = shdr.sh_addr + off as u64;
+            for label in symbols.at(addr) {
+                writeln!(out, "\n{:016x} <{}>:", addr, label)?;
+            }
+            let rest = &data[off..];
+            // a compressed (16-bit) instruction has low bits other than 11
+            if rest.len() < 4 || rest[0] & 3 != 3 {
+                let half = u16::from_le_bytes([rest[0], *rest.get(1).unwrap_or(&0)]);
+                writeln!(out, "{:8x}:\t{:04x}    \t.short {:#06x}", addr, half, half)?;
+                off += 2;
+                continue;
+            }
+            let isn = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+            writeln!(out, "{:8x}:\t{:08x}\t{}", addr, isn, disasm(isn, addr))?;
+            off += 4;
+        }
+    }
+    out.flush()
+}
@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Source locations for guest addresses, from the DWARF debug info in the
+// guest ELF (.debug_line, .debug_info and friends). Used wherever an address
+// is shown to a person: crash reports, traces, the debugger. Guests built
+// without debug info just get symbol+offset as before.
+
+use std::borrow::Cow;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+
+use addr2line::gimli;
+use elf::ElfBytes;
+use elf::endian::LittleEndian;
+
+// the sections are copied out and kept for the life of the process
+type Reader = gimli::EndianSlice<'static, gimli::LittleEndian>;
+
+static CONTEXT: OnceLock<Mutex<addr2line::Context<Reader>>> = OnceLock::new();
+
+/// One function at an address: the innermost comes first, followed by the
+/// functions it was inlined into.
+pub(crate) struct Frame {
+    pub function: Option<String>,
+    /// "file:line"
+    pub location: Option<String>,
+}
+
+impl std::fmt::Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.function, &self.location) {
+            (Some(func), Some(loc)) => write!(f, "{} at {}", func, loc),
+            (Some(func), None) => write!(f, "{}", func),
+            (None, Some(loc)) => write!(f, "at {}", loc),
+            (None, None) => write!(f, "??"),
+        }
+    }
+}
+
+/// Read the debug info of the guest, if it has any.
+pub(crate) fn load(elf_f: &ElfBytes<LittleEndian>) {
+    let has_debug_info = |name| elf_f.section_header_by_name(name).ok().flatten().is_some();
+    if !has_debug_info(".debug_info") && !has_debug_info(".debug_line") {
+        return;
+    }
+    let section = |id: gimli::SectionId| -> Result<Reader, gimli::Error> {
+        let data = elf_f
+            .section_header_by_name(id.name())
+            .ok()
+            .flatten()
+            .and_then(|shdr| elf_f.section_data(&shdr).ok())
+            // compressed debug sections are not supported
+            .filter(|(_, compression)| compression.is_none())
+            .map_or(&[][..], |(data, _)| data);
+        Ok(Reader::new(Vec::leak(data.to_vec()), gimli::LittleEndian))
+    };
+    let Ok(dwarf) = gimli::Dwarf::load(section) else {
+        return;
+    };
+    if let Ok(ctx) = addr2line::Context::from_dwarf(dwarf) {
+        let _ = CONTEXT.set(Mutex::new(ctx));
+    }
+}
+
+fn location(loc: addr2line::Location) -> Option<String> {
+    Some(format!("{}:{}", loc.file?, loc.line?))
+}
+
+/// The functions and source lines at addr, innermost first
+pub(crate) fn frames(addr: u64) -> Vec<Frame> {
+    let Some(ctx) = CONTEXT.get() else {
+        return Vec::new();
+    };
+    let ctx = ctx.lock().unwrap_or_else(|e| e.into_inner());
+    let mut out = Vec::new();
+    let Ok(mut frames) = ctx.find_frames(addr).skip_all_loads() else {
+        return out;
+    };
+    while let Ok(Some(frame)) = frames.next() {
+        let function = frame.function.and_then(|f| f.demangle().ok().map(Cow::into_owned));
+        out.push(Frame { function, location: frame.location.and_then(location) });
+    }
+    out
+}
+
+/// The source line of addr, as "file:line"
+pub(crate) fn line(addr: u64) -> Option<String> {
+    let ctx = CONTEXT.get()?.lock().unwrap_or_else(|e| e.into_inner());
+    ctx.find_location(addr).ok().flatten().and_then(location)
+}
+
+/// The line table rows covering [low, high): start address, length in
+/// bytes, file and line
+pub(crate) fn line_table(low: u64, high: u64) -> Vec<(u64, u64, String, u32)> {
+    let Some(ctx) = CONTEXT.get() else {
+        return Vec::new();
+    };
+    let ctx = ctx.lock().unwrap_or_else(|e| e.into_inner());
+    let Ok(rows) = ctx.find_location_range(low, high) else {
+        return Vec::new();
+    };
+    rows.filter_map(|(addr, len, loc)| Some((addr, len, loc.file?.to_string(), loc.line?))).collect()
+}
+
+pub(crate) fn available() -> bool {
+    CONTEXT.get().is_some()
+}
@@ -0,0 +1,449 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// GDB remote serial protocol stub (--gdb). Listens on a localhost TCP port,
+// or on a Unix socket when given a path, waits for one debugger to connect
+// and then runs the guest one instruction at a time on its behalf, without
+// the translation cache or JIT so every instruction can be checked against
+// breakpoints and watchpoints.
+//
+// The floating point registers are described too, so gdb shows and sets
+// them as on real hardware, but the F and D extensions are not emulated yet:
+// they start at zero and only the debugger ever changes them.
+
+use std::collections::HashSet;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+
+use crate::adt;
+use crate::decode::Instruction;
+use crate::interp;
+use crate::mapped;
+use crate::syscall;
+use crate::utils::ConvertibleError;
+use crate::utils::terminal_error;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// Register numbers after pc (32): f0-f31, then fflags, frm and fcsr
+const FIRST_FPR: usize = 33;
+const FFLAGS: usize = FIRST_FPR + 32;
+const FCSR: usize = FFLAGS + 2;
+
+trait Conn: Read + Write {}
+impl<T: Read + Write> Conn for T {}
+
+#[derive(Clone, Copy, PartialEq)]
+enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+struct Watch {
+    kind: WatchKind,
+    addr: u64,
+    len: u64,
+}
+
+struct Stub {
+    conn: Box<dyn Conn>,
+    /// A byte read while checking for ^C that was not one
+    pending: Option<u8>,
+    ack: bool,
+    fprs: [u64; 32],
+    /// fflags, frm and fcsr
+    fcsrs: [u32; 3],
+    breakpoints: HashSet<u64>,
+    watchpoints: Vec<Watch>,
+}
+
+fn target_xml() -> String {
+    let mut x = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target version=\"1.0\">\
+         <architecture>riscv:rv64</architecture><feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for (i, name) in interp::REG_NAMES.iter().enumerate() {
+        let ty = match i {
+            1 => "code_ptr",
+            2 => "data_ptr",
+            _ => "int",
+        };
+        x += &format!("<reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"/>", name, ty, i);
+    }
+    x += "<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"32\"/></feature>";
+    x += "<feature name=\"org.gnu.gdb.riscv.fpu\">";
+    for i in 0..32 {
+        x += &format!("<reg name=\"f{}\" bitsize=\"64\" type=\"ieee_double\" regnum=\"{}\"/>", i, FIRST_FPR + i);
+    }
+    for (i, name) in ["fflags", "frm", "fcsr"].iter().enumerate() {
+        x += &format!("<reg name=\"{}\" bitsize=\"32\" type=\"int\" regnum=\"{}\"/>", name, FFLAGS + i);
+    }
+    x += "</feature></target>";
+    x
+}
+
+fn hex64(v: u64) -> String {
+    v.to_le_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex32(v: u32) -> String {
+    v.to_le_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn unhex64(s: &str) -> Option<u64> {
+    let b = unhex(s)?;
+    let mut v = [0u8; 8];
+    v.get_mut(..b.len())?.copy_from_slice(&b);
+    Some(u64::from_le_bytes(v))
+}
+
+/// "addr,len" in big-endian hex, as m/M/Z packets use
+fn addr_len(s: &str) -> Option<(u64, u64)> {
+    let (a, l) = s.split_once(',')?;
+    Some((u64::from_str_radix(a, 16).ok()?, u64::from_str_radix(l, 16).ok()?))
+}
+
+impl Stub {
+    fn byte(&mut self) -> u8 {
+        if let Some(b) = self.pending.take() {
+            return b;
+        }
+        let mut b = [0u8];
+        if self.conn.read(&mut b).e("Error reading from debugger") == 0 {
+            // debugger went away, nothing left to do
+            crate::exit(0);
+        }
+        b[0]
+    }
+
+    /// Next packet body; a bare ^C comes back as "\x03".
+    fn recv(&mut self) -> String {
+        loop {
+            match self.byte() {
+                b'$' => {}
+                3 => return "\x03".into(),
+                _ => continue,
+            }
+            let mut body = Vec::new();
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    b => body.push(b),
+                }
+            }
+            let sum = [self.byte(), self.byte()];
+            let sum = u8::from_str_radix(std::str::from_utf8(&sum).unwrap_or(""), 16).ok();
+            let ok = sum == Some(body.iter().fold(0u8, |a, b| a.wrapping_add(*b)));
+            if self.ack {
+                self.conn.write_all(if ok { b"+" } else { b"-" }).e("Error writing to debugger");
+            }
+            if ok {
+                return String::from_utf8_lossy(&body).into_owned();
+            }
+        }
+    }
+
+    fn send(&mut self, body: &str) {
+        let sum = body.bytes().fold(0u8, |a, b| a.wrapping_add(b));
+        let packet = format!("${}#{:02x}", body, sum);
+        self.conn.write_all(packet.as_bytes()).e("Error writing to debugger");
+        self.conn.flush().e("Error writing to debugger");
+        if self.ack {
+            // '+' or '-'; a NAK just gets ignored, TCP does not lose data.
+            // A packet sent before the ack (while the guest ran) comes first.
+            match self.byte() {
+                b'+' | b'-' => {}
+                b => self.pending = Some(b),
+            }
+        }
+    }
+
+    /// Whether the debugger sent a ^C while the guest was running
+    fn interrupted(&mut self, fd: i32) -> bool {
+        if self.pending.is_some() {
+            return false;
+        }
+        let mut pfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut pfd, 1, 0) } <= 0 {
+            return false;
+        }
+        match self.byte() {
+            3 => true,
+            b => {
+                // the start of a packet; keep it for recv
+                self.pending = Some(b);
+                false
+            }
+        }
+    }
+
+    /// Register n in hex, as p and g packets send it
+    fn register(&self, n: usize, registers: &[u64; 32], pc: u64) -> Option<String> {
+        Some(match n {
+            0..32 => hex64(registers[n]),
+            32 => hex64(pc),
+            FIRST_FPR..FFLAGS => hex64(self.fprs[n - FIRST_FPR]),
+            FFLAGS..=FCSR => hex32(self.fcsrs[n - FFLAGS]),
+            _ => return None,
+        })
+    }
+
+    /// Set register n from hex; false if there is no such register
+    fn set_register(&mut self, n: usize, hex: &str, registers: &mut [u64; 32], pc: &mut u64) -> bool {
+        let Some(v) = unhex64(hex) else {
+            return false;
+        };
+        match n {
+            // zero stays zero
+            0 => {}
+            1..32 => registers[n] = v,
+            32 => *pc = v,
+            FIRST_FPR..FFLAGS => self.fprs[n - FIRST_FPR] = v,
+            FFLAGS..=FCSR => self.fcsrs[n - FFLAGS] = v as u32,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Run (or single-step) the guest until something stops it, and return
+    /// the stop reply.
+    fn resume(&mut self, mem: *mut libc::c_void, registers: &mut [u64; 32], pc: &mut u64, step: bool, fd: i32) -> String {
+        let mut first = true;
+        let mut n: u64 = 0;
+        loop {
+            if !first && self.breakpoints.contains(pc) {
+                return format!("T{:02x}swbreak:;", SIGTRAP);
+            }
+            first = false;
+            n += 1;
+            if n.is_multiple_of(4096) && self.interrupted(fd) {
+                return format!("S{:02x}", SIGINT);
+            }
+            if !mapped(*pc, 4) {
+                return format!("S{:02x}", libc::SIGSEGV);
+            }
+            let inst = interp::fetch(mem, *pc);
+            match inst {
+                Instruction::Ebreak => return format!("S{:02x}", SIGTRAP),
+                Instruction::Ecall if syscall::is_exit(registers[17]) => {
+                    self.send(&format!("W{:02x}", registers[10] as u8));
+                }
+                _ => {}
+            }
+            let hit = interp::access(inst, registers).and_then(|(addr, len, store)| {
+                self.watchpoints
+                    .iter()
+                    .find(|w| {
+                        // overlapping, without overflowing at the top of
+                        // the address space
+                        (addr.wrapping_sub(w.addr) < w.len || w.addr.wrapping_sub(addr) < len)
+                            && match w.kind {
+                                WatchKind::Write => store,
+                                WatchKind::Read => !store,
+                                WatchKind::Access => true,
+                            }
+                    })
+                    .map(|w| (w.kind, addr.max(w.addr)))
+            });
+            interp::execute(inst, mem, registers, pc);
+            if let Some((kind, addr)) = hit {
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                return format!("T{:02x}{}:{:x};", SIGTRAP, name, addr);
+            }
+            if step {
+                return format!("S{:02x}", SIGTRAP);
+            }
+        }
+    }
+
+    fn read_memory(&self, mem: *mut libc::c_void, addr: u64, len: u64) -> String {
+        if !mapped(addr, len) {
+            return "E14".into();
+        }
+        (0..len).map(|i| format!("{:02x}", unsafe { *(adt(addr + i, mem) as *const u8) })).collect()
+    }
+
+    fn write_memory(&self, mem: *mut libc::c_void, addr: u64, data: &[u8]) -> String {
+        if !mapped(addr, data.len() as u64) {
+            return "E14".into();
+        }
+        for (i, b) in data.iter().enumerate() {
+            unsafe { *(adt(addr + i as u64, mem) as *mut u8) = *b };
+        }
+        "OK".into()
+    }
+
+    /// Z/z packets: insert or remove a breakpoint or watchpoint
+    fn point(&mut self, insert: bool, args: &str) -> String {
+        let Some((kind, rest)) = args.split_once(',') else {
+            return "E01".into();
+        };
+        let Some((addr, len)) = addr_len(rest.split(';').next().unwrap()) else {
+            return "E01".into();
+        };
+        let kind = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                return "OK".into();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        if insert {
+            self.watchpoints.push(Watch { kind, addr, len });
+        } else {
+            self.watchpoints.retain(|w| !(w.kind == kind && w.addr == addr && w.len == len));
+        }
+        "OK".into()
+    }
+
+    fn query(&self, q: &str) -> String {
+        if q.starts_with("Supported") {
+            return "PacketSize=4000;qXfer:features:read+;swbreak+;QStartNoAckMode+".into();
+        }
+        if let Some(rest) = q.strip_prefix("Xfer:features:read:target.xml:") {
+            let Some((off, len)) = addr_len(rest) else {
+                return "E01".into();
+            };
+            let xml = target_xml();
+            let off = (off as usize).min(xml.len());
+            let end = (off + len as usize).min(xml.len());
+            return format!("{}{}", if end == xml.len() { 'l' } else { 'm' }, &xml[off..end]);
+        }
+        match q {
+            "Attached" => "0".into(),
+            "C" => "QC1".into(),
+            "fThreadInfo" => "m1".into(),
+            "sThreadInfo" => "l".into(),
+            _ => String::new(),
+        }
+    }
+}
+
+/// Wait for a debugger on `target` (a port number or a socket path) and let
+/// it drive the guest. Never returns.
+pub(crate) fn serve(target: &str, mem: *mut libc::c_void, registers: &mut [u64; 32], pc: &mut u64) -> ! {
+    use std::os::fd::AsRawFd;
+    let (conn, fd): (Box<dyn Conn>, i32) = if let Ok(port) = target.parse::<u16>() {
+        let l = TcpListener::bind(("127.0.0.1", port)).e("Unable to listen for debugger");
+        eprintln!("riscv-um: waiting for gdb on localhost:{}", port);
+        let (s, _) = l.accept().e("Unable to accept debugger connection");
+        let _ = s.set_nodelay(true);
+        let fd = s.as_raw_fd();
+        (Box::new(s), fd)
+    } else {
+        // a socket left behind by an earlier run is replaced, anything else
+        // is not ours to remove
+        match std::fs::symlink_metadata(target) {
+            Ok(m) if m.file_type().is_socket() => {
+                let _ = std::fs::remove_file(target);
+            }
+            Ok(_) => terminal_error("Unable to listen for debugger: address in use"),
+            Err(_) => {}
+        }
+        let l = UnixListener::bind(target).e("Unable to listen for debugger");
+        eprintln!("riscv-um: waiting for gdb on {}", target);
+        let (s, _) = l.accept().e("Unable to accept debugger connection");
+        let fd = s.as_raw_fd();
+        (Box::new(s), fd)
+    };
+    let mut stub = Stub {
+        conn,
+        pending: None,
+        ack: true,
+        fprs: [0; 32],
+        fcsrs: [0; 3],
+        breakpoints: HashSet::new(),
+        watchpoints: Vec::new(),
+    };
+    loop {
+        let p = stub.recv();
+        let (cmd, args) = p.split_at(p.chars().next().map_or(0, |c| c.len_utf8()));
+        let reply = match cmd {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => (0..=FCSR).filter_map(|n| stub.register(n, registers, *pc)).collect(),
+            "G" => {
+                let mut rest = args;
+                for n in 0..=FCSR {
+                    let width = if n < FFLAGS { 16 } else { 8 };
+                    let Some((hex, tail)) = rest.split_at_checked(width) else {
+                        break;
+                    };
+                    stub.set_register(n, hex, registers, pc);
+                    rest = tail;
+                }
+                "OK".into()
+            }
+            "p" => match usize::from_str_radix(args, 16).ok().and_then(|n| stub.register(n, registers, *pc)) {
+                Some(hex) => hex,
+                None => "E01".into(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(n, v)| Some((usize::from_str_radix(n, 16).ok()?, v)));
+                match parsed {
+                    Some((n, v)) if stub.set_register(n, v, registers, pc) => "OK".into(),
+                    _ => "E01".into(),
+                }
+            }
+            "m" => match addr_len(args) {
+                Some((addr, len)) => stub.read_memory(mem, addr, len),
+                None => "E01".into(),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(al, data)| Some((addr_len(al)?, unhex(data)?)));
+                match parsed {
+                    Some(((addr, len), data)) if data.len() as u64 == len => stub.write_memory(mem, addr, &data),
+                    _ => "E01".into(),
+                }
+            }
+            "c" | "s" => {
+                if let Some(addr) = args.split(';').next().and_then(|a| u64::from_str_radix(a, 16).ok()) {
+                    *pc = addr;
+                }
+                stub.resume(mem, registers, pc, cmd == "s", fd)
+            }
+            "Z" | "z" => stub.point(cmd == "Z", args),
+            "q" => stub.query(args),
+            "Q" if args == "StartNoAckMode" => {
+                stub.send("OK");
+                stub.ack = false;
+                continue;
+            }
+            "H" | "T" => "OK".into(),
+            "D" => {
+                stub.send("OK");
+                break;
+            }
+            "k" => crate::exit(0),
+            // a ^C while already stopped
+            "\x03" => format!("S{:02x}", SIGINT),
+            _ => String::new(),
+        };
+        stub.send(&reply);
+    }
+    // detached: carry on without the debugger
+    loop {
+        interp::step(mem, registers, pc);
+    }
+}
@@ -0,0 +1,312 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// The interpreter: executes one decoded instruction.
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use crate::adt;
+use crate::block;
+use crate::crash;
+use crate::decode;
+use crate::decode::Cond;
+use crate::decode::Instruction;
+use crate::limits;
+use crate::syscall;
+use crate::utils;
+
+/// ABI names of the integer registers
+pub(crate) const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "s2",
+    "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+/// Print pc and the integer registers to stderr, four to a line.
+pub(crate) fn dump_registers(registers: &[u64; 32], pc: u64) {
+    eprintln!("pc   {:016x}", pc);
+    for (i, chunk) in registers.chunks(4).enumerate() {
+        let line: Vec<String> =
+            chunk.iter().enumerate().map(|(j, v)| format!("{:<4} {:016x}", REG_NAMES[i * 4 + j], v)).collect();
+        eprintln!("{}", line.join("  "));
+    }
+}
+
+/// Fetch, decode and execute the instruction at pc, bypassing the
+/// translation cache. For debuggers and per-instruction hooks.
+pub(crate) fn step(mem: *mut libc::c_void, registers: &mut [u64; 32], pc: &mut u64) {
+    execute(fetch(mem, *pc), mem, registers, pc);
+}
+
+/// What a per-instruction mode (--trace, --profile, --stats) does around
+/// each instruction the hooked loop runs
+pub(crate) trait Hook {
+    /// Before inst, at pc at, runs
+    fn before(&mut self, _inst: Instruction, _at: u64, _mem: *mut libc::c_void, _registers: &[u64; 32]) {}
+
+    /// After inst ran, leaving the registers and pc behind
+    fn after(&mut self, _inst: Instruction, _at: u64, _mem: *mut libc::c_void, _registers: &[u64; 32], _pc: u64) {}
+}
+
+/// Run the guest one instruction at a time, bypassing the translation cache
+/// so nothing is fused or compiled, until a limit stops it. Returns why and
+/// how many instructions were retired.
+pub(crate) fn run(
+    mem: *mut libc::c_void,
+    registers: &mut [u64; 32],
+    pc: &mut u64,
+    max_insns: u64,
+    hook: &mut impl Hook,
+) -> (block::Stop, u64) {
+    let mut retired = 0;
+    loop {
+        if retired == max_insns {
+            return (block::Stop::InsnLimit, retired);
+        }
+        if limits::expired() {
+            return (block::Stop::Timeout, retired);
+        }
+        let at = *pc;
+        let inst = fetch(mem, at);
+        hook.before(inst, at, mem, registers);
+        execute(inst, mem, registers, pc);
+        retired += 1;
+        hook.after(inst, at, mem, registers, *pc);
+    }
+}
+
+/// A hook's state, kept where it can be reported on the way out of the
+/// process (guest exit, crash, limit or the watchdog). Each use claims it, so
+/// a report never sees it half way through a hook call.
+pub(crate) struct Shared<T> {
+    busy: AtomicBool,
+    state: UnsafeCell<Option<T>>,
+}
+
+// the state is only touched by whoever claimed busy
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    pub(crate) const fn new() -> Self {
+        Shared { busy: AtomicBool::new(false), state: UnsafeCell::new(None) }
+    }
+
+    pub(crate) fn install(&self, value: T) {
+        self.with_state(|state| *state = Some(value));
+    }
+
+    /// Run f on the state, unless there is none or it is already claimed (a
+    /// fault inside the hook, or the other thread)
+    pub(crate) fn with(&self, f: impl FnOnce(&mut T)) {
+        self.with_state(|state| {
+            if let Some(state) = state {
+                f(state);
+            }
+        });
+    }
+
+    fn with_state(&self, f: impl FnOnce(&mut Option<T>)) {
+        if !self.busy.swap(true, Ordering::Acquire) {
+            f(unsafe { &mut *self.state.get() });
+            self.busy.store(false, Ordering::Release);
+        }
+    }
+}
+
+impl<T: Hook> Hook for &Shared<T> {
+    fn before(&mut self, inst: Instruction, at: u64, mem: *mut libc::c_void, registers: &[u64; 32]) {
+        self.with(|h| h.before(inst, at, mem, registers));
+    }
+
+    fn after(&mut self, inst: Instruction, at: u64, mem: *mut libc::c_void, registers: &[u64; 32], pc: u64) {
+        self.with(|h| h.after(inst, at, mem, registers, pc));
+    }
+}
+
+/// The decoded instruction at pc, noted as the one running for crash
+/// reports.
+pub(crate) fn fetch(mem: *mut libc::c_void, pc: u64) -> Instruction {
+    crash::CURRENT.store(pc, Ordering::Relaxed);
+    decode::decode(unsafe { *(adt(pc, mem) as *const u32) })
+}
+
+/// Whether a conditional branch is about to be taken
+pub(crate) fn taken(inst: Instruction, registers: &[u64; 32]) -> Option<bool> {
+    use Instruction::*;
+    let r = |n: u8| registers[n as usize];
+    Some(match inst {
+        Beq { rs1, rs2, .. } => r(rs1) == r(rs2),
+        Bne { rs1, rs2, .. } => r(rs1) != r(rs2),
+        Blt { rs1, rs2, .. } => (r(rs1) as i64) < (r(rs2) as i64),
+        _ => return None,
+    })
+}
+
+/// The guest address and size a load or store is about to touch, and
+/// whether it is a store
+pub(crate) fn access(inst: Instruction, registers: &[u64; 32]) -> Option<(u64, u64, bool)> {
+    use Instruction::*;
+    let ea = |rs1: u8, imm: i32| registers[rs1 as usize].wrapping_add(imm as i64 as u64);
+    Some(match inst {
+        Lw { rs1, imm, .. } => (ea(rs1, imm), 4, false),
+        Ld { rs1, imm, .. } => (ea(rs1, imm), 8, false),
+        Sb { rs1, imm, .. } => (ea(rs1, imm), 1, true),
+        Sw { rs1, imm, .. } => (ea(rs1, imm), 4, true),
+        Sd { rs1, imm, .. } => (ea(rs1, imm), 8, true),
+        _ => return None,
+    })
+}
+
+#[inline(always)]
+pub(crate) fn execute(inst: Instruction, mem: *mut libc::c_void, registers: &mut [u64; 32], pc: &mut u64) {
+    use Instruction::*;
+    let r = |registers: &[u64; 32], n: u8| registers[n as usize];
+    let sx = |imm: i32| imm as i64 as u64;
+    match inst {
+        Lw { rd, rs1, imm } => {
+            let addr = r(registers, rs1).wrapping_add(sx(imm));
+            let v = unsafe { *(adt(addr, mem) as *const u32) } as u64;
+            utils::write_register_safe(registers, rd as usize, utils::sign_extend_32(v));
+        }
+        Ld { rd, rs1, imm } => {
+            let addr = r(registers, rs1).wrapping_add(sx(imm));
+            let v = unsafe { *(adt(addr, mem) as *const u64) };
+            utils::write_register_safe(registers, rd as usize, v);
+        }
+        Addi { rd, rs1, imm } => {
+            utils::write_register_safe(registers, rd as usize, r(registers, rs1).wrapping_add(sx(imm)));
+        }
+        Slli { rd, rs1, shamt } => {
+            utils::write_register_safe(registers, rd as usize, r(registers, rs1) << shamt);
+        }
+        Srli { rd, rs1, shamt } => {
+            utils::write_register_safe(registers, rd as usize, r(registers, rs1) >> shamt);
+        }
+        Andi { rd, rs1, imm } => {
+            utils::write_register_safe(registers, rd as usize, r(registers, rs1) & sx(imm));
+        }
+        Auipc { rd, imm } => {
+            utils::write_register_safe(registers, rd as usize, pc.wrapping_add(sx(imm)));
+        }
+        Addiw { rd, rs1, imm } => {
+            let v = (r(registers, rs1) as u32).wrapping_add(imm as u32);
+            utils::write_register_safe(registers, rd as usize, utils::sign_extend_32(v as u64));
+        }
+        Slliw { rd, rs1, shamt } => {
+            let v = (r(registers, rs1) as u32) << shamt;
+            utils::write_register_safe(registers, rd as usize, utils::sign_extend_32(v as u64));
+        }
+        Sb { rs1, rs2, imm } => {
+            let addr = r(registers, rs1).wrapping_add(sx(imm));
+            block::store(addr);
+            unsafe { *(adt(addr, mem) as *mut u8) = r(registers, rs2) as u8 };
+        }
+        Sw { rs1, rs2, imm } => {
+            let addr = r(registers, rs1).wrapping_add(sx(imm));
+            block::store(addr);
+            unsafe { *(adt(addr, mem) as *mut u32) = r(registers, rs2) as u32 };
+        }
+        Sd { rs1, rs2, imm } => {
+            let addr = r(registers, rs1).wrapping_add(sx(imm));
+            block::store(addr);
+            unsafe { *(adt(addr, mem) as *mut u64) = r(registers, rs2) };
+        }
+        Add { rd, rs1, rs2 } => {
+            let v = r(registers, rs1).wrapping_add(r(registers, rs2));
+            utils::write_register_safe(registers, rd as usize, v);
+        }
+        Sub { rd, rs1, rs2 } => {
+            let v = r(registers, rs1).wrapping_sub(r(registers, rs2));
+            utils::write_register_safe(registers, rd as usize, v);
+        }
+        Sll { rd, rs1, rs2 } => {
+            let v = r(registers, rs1) << (r(registers, rs2) & 0x3f);
+            utils::write_register_safe(registers, rd as usize, v);
+        }
+        Lui { rd, imm } => {
+            utils::write_register_safe(registers, rd as usize, sx(imm));
+        }
+        Addw { rd, rs1, rs2 } => {
+            let v = (r(registers, rs1) as u32).wrapping_add(r(registers, rs2) as u32);
+            utils::write_register_safe(registers, rd as usize, utils::sign_extend_32(v as u64));
+        }
+        Subw { rd, rs1, rs2 } => {
+            let v = (r(registers, rs1) as u32).wrapping_sub(r(registers, rs2) as u32);
+            utils::write_register_safe(registers, rd as usize, utils::sign_extend_32(v as u64));
+        }
+        Beq { rs1, rs2, imm } => {
+            if r(registers, rs1) == r(registers, rs2) {
+                *pc = pc.wrapping_add(sx(imm));
+                return;
+            }
+        }
+        Bne { rs1, rs2, imm } => {
+            if r(registers, rs1) != r(registers, rs2) {
+                *pc = pc.wrapping_add(sx(imm));
+                return;
+            }
+        }
+        Blt { rs1, rs2, imm } => {
+            if (r(registers, rs1) as i64) < (r(registers, rs2) as i64) {
+                *pc = pc.wrapping_add(sx(imm));
+                return;
+            }
+        }
+        Jalr { rd, rs1, imm } => {
+            let target = r(registers, rs1).wrapping_add(sx(imm)) & !1;
+            utils::write_register_safe(registers, rd as usize, *pc + 4);
+            *pc = target;
+            return;
+        }
+        Jal { rd, imm } => {
+            utils::write_register_safe(registers, rd as usize, *pc + 4);
+            *pc = pc.wrapping_add(sx(imm));
+            return;
+        }
+        LoadImm { rd, value } => {
+            utils::write_register_safe(registers, rd as usize, sx(value));
+            *pc += 8;
+            return;
+        }
+        Call { rd, rt, offset } => {
+            utils::write_register_safe(registers, rt as usize, pc.wrapping_add(decode::hi_part(offset) as u64));
+            utils::write_register_safe(registers, rd as usize, *pc + 8);
+            *pc = pc.wrapping_add(sx(offset)) & !1;
+            return;
+        }
+        AuipcLd { rd, rt, offset } => {
+            utils::write_register_safe(registers, rt as usize, pc.wrapping_add(decode::hi_part(offset) as u64));
+            let v = unsafe { *(adt(pc.wrapping_add(sx(offset)), mem) as *const u64) };
+            utils::write_register_safe(registers, rd as usize, v);
+            *pc += 8;
+            return;
+        }
+        Zext { rd, rs1, shamt } => {
+            utils::write_register_safe(registers, rd as usize, r(registers, rs1) & (u64::MAX >> shamt));
+            *pc += 8;
+            return;
+        }
+        BranchImm { cond, rs1, rt, k, imm } => {
+            utils::write_register_safe(registers, rt as usize, k as i64 as u64);
+            let (a, k) = (r(registers, rs1) as i64, k as i64);
+            let taken = match cond {
+                Cond::Eq => a == k,
+                Cond::Ne => a != k,
+                Cond::Lt => a < k,
+                Cond::Gt => a > k,
+            };
+            // the branch itself is the second instruction
+            *pc += if taken { 4u64.wrapping_add(imm as i64 as u64) } else { 8 };
+            return;
+        }
+        Fence => {}
+        FenceI => block::invalidate(),
+        Ecall => syscall::ecall(registers, mem),
+        Ebreak => crash::report(libc::SIGTRAP, "ebreak", mem, registers, *pc),
+        Csr => {}
+        Unimplemented(isn) => {
+            crash::report(libc::SIGILL, &format!("illegal instruction {:#010x}", isn), mem, registers, *pc)
+        }
+    }
+    *pc += 4;
+}
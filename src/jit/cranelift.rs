@@ -0,0 +1,325 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Cranelift backend, for hosts the handwritten one does not cover. Guest
+// registers are loaded from the register array the first time a block uses
+// them, kept in SSA values and written back before returning, so Cranelift
+// gets to allocate them to host registers. Guest memory accesses go through
+// the same address translation as adt(), and stores check the code page
+// bitmap like the interpreter does.
+//
+// Memory is still accessed little endian, so this does not lift the host
+// endianness requirement; nothing else here depends on the host.
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::types;
+use cranelift_codegen::ir::AbiParam;
+use cranelift_codegen::ir::InstBuilder;
+use cranelift_codegen::ir::MemFlags;
+use cranelift_codegen::ir::Value;
+use cranelift_codegen::settings;
+use cranelift_codegen::settings::Configurable;
+use cranelift_codegen::Context;
+use cranelift_frontend::FunctionBuilder;
+use cranelift_frontend::FunctionBuilderContext;
+use cranelift_jit::JITBuilder;
+use cranelift_jit::JITModule;
+use cranelift_module::Module;
+
+use crate::block;
+use crate::decode::Instruction;
+use crate::GUARD_OFFSET;
+use crate::STACK_OFFSET;
+use crate::stack_bottom;
+use crate::stack_size;
+use crate::utils::terminal_error;
+
+use super::NativeBlock;
+
+pub(crate) struct Compiler {
+    module: JITModule,
+    ctx: Context,
+    fctx: FunctionBuilderContext,
+}
+
+fn new_module() -> JITModule {
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").unwrap();
+    flags.set("is_pic", "false").unwrap();
+    let isa = cranelift_native::builder()
+        .unwrap_or_else(|e| terminal_error(&format!("Cranelift does not support this host: {}", e)))
+        .finish(settings::Flags::new(flags))
+        .unwrap_or_else(|e| terminal_error(&format!("Unable to set up Cranelift: {}", e)));
+    JITModule::new(JITBuilder::with_isa(isa, cranelift_module::default_libcall_names()))
+}
+
+impl Compiler {
+    pub(crate) fn new() -> Self {
+        let module = new_module();
+        let ctx = module.make_context();
+        Self { module, ctx, fctx: FunctionBuilderContext::new() }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        let old = std::mem::replace(&mut self.module, new_module());
+        // only called between blocks, so none of this code is running
+        unsafe { old.free_memory() };
+    }
+
+    pub(crate) fn compile(&mut self, pc: u64, insts: &[Instruction]) -> Option<NativeBlock> {
+        if !insts.iter().all(|i| supported(*i)) {
+            return None;
+        }
+        let ptr = self.module.target_config().pointer_type();
+        self.module.clear_context(&mut self.ctx);
+        self.ctx.func.signature.params.push(AbiParam::new(ptr));
+        self.ctx.func.signature.params.push(AbiParam::new(ptr));
+        self.ctx.func.signature.returns.push(AbiParam::new(types::I64));
+        let id = self.module.declare_anonymous_function(&self.ctx.func.signature).ok()?;
+
+        let mut b = FunctionBuilder::new(&mut self.ctx.func, &mut self.fctx);
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        b.seal_block(entry);
+        let (regs, mem) = (b.block_params(entry)[0], b.block_params(entry)[1]);
+        let mut e = Emitter { b, regs, mem, ptr, vals: [None; 32], dirty: [false; 32] };
+        let mut pc = pc;
+        let mut next = None;
+        for inst in insts {
+            next = e.inst(*inst, pc);
+            pc += 4;
+        }
+        // ran off the end of a page
+        let next = next.unwrap_or_else(|| e.b.ins().iconst(types::I64, pc as i64));
+        e.write_back();
+        e.b.ins().return_(&[next]);
+        e.b.finalize();
+
+        self.module.define_function(id, &mut self.ctx).ok()?;
+        self.module.finalize_definitions().ok()?;
+        let code = self.module.get_finalized_function(id);
+        Some(unsafe { std::mem::transmute::<*const u8, NativeBlock>(code) })
+    }
+}
+
+fn supported(inst: Instruction) -> bool {
+    use Instruction::*;
+    // blocks are compiled from unfused instructions, so no fused ones here
+    !matches!(
+        inst,
+        LoadImm { .. } | Call { .. } | AuipcLd { .. } | Zext { .. } | BranchImm { .. } | FenceI | Ecall | Ebreak | Unimplemented(_)
+    )
+}
+
+struct Emitter<'a> {
+    b: FunctionBuilder<'a>,
+    regs: Value,
+    mem: Value,
+    ptr: types::Type,
+    /// Guest register values loaded or computed so far
+    vals: [Option<Value>; 32],
+    dirty: [bool; 32],
+}
+
+impl Emitter<'_> {
+    fn get(&mut self, n: u8) -> Value {
+        if n == 0 {
+            return self.b.ins().iconst(types::I64, 0);
+        }
+        match self.vals[n as usize] {
+            Some(v) => v,
+            None => {
+                let v = self.b.ins().load(types::I64, MemFlags::trusted(), self.regs, n as i32 * 8);
+                self.vals[n as usize] = Some(v);
+                v
+            }
+        }
+    }
+
+    fn set(&mut self, n: u8, v: Value) {
+        if n != 0 {
+            self.vals[n as usize] = Some(v);
+            self.dirty[n as usize] = true;
+        }
+    }
+
+    fn write_back(&mut self) {
+        for n in 1..32 {
+            if self.dirty[n] {
+                let v = self.vals[n].unwrap();
+                self.b.ins().store(MemFlags::trusted(), v, self.regs, n as i32 * 8);
+            }
+        }
+    }
+
+    fn sext32(&mut self, v: Value) -> Value {
+        let v = self.b.ins().ireduce(types::I32, v);
+        self.b.ins().sextend(types::I64, v)
+    }
+
+    /// Offset into guest memory of rs1 + imm, as adt() computes it
+    fn offset(&mut self, rs1: u8, imm: i32) -> Value {
+        let base = self.get(rs1);
+        let addr = self.b.ins().iadd_imm(base, imm as i64);
+        let program = self.b.ins().icmp_imm(IntCC::UnsignedLessThan, addr, 1 << 24);
+        let below = self.b.ins().iadd_imm(addr, -(stack_bottom as i64));
+        let stack = self.b.ins().icmp_imm(IntCC::UnsignedLessThan, below, stack_size as i64);
+        let moved = self.b.ins().iadd_imm(below, STACK_OFFSET as i64);
+        let guard = self.b.ins().iconst(types::I64, GUARD_OFFSET as i64);
+        let other = self.b.ins().select(stack, moved, guard);
+        self.b.ins().select(program, addr, other)
+    }
+
+    fn host(&mut self, off: Value) -> Value {
+        let off = if self.ptr == types::I64 { off } else { self.b.ins().ireduce(self.ptr, off) };
+        self.b.ins().iadd(self.mem, off)
+    }
+
+    /// Raise the stale flag if the offset is on a translated code page.
+    fn check_code_write(&mut self, off: Value) {
+        let page = self.b.ins().ushr_imm(off, 12);
+        let in_range = self.b.ins().icmp_imm(IntCC::UnsignedLessThan, page, block::PAGES as i64);
+        let last = self.b.ins().iconst(types::I64, block::PAGES as i64 - 1);
+        let page = self.b.ins().umin(page, last);
+        let word = self.b.ins().ushr_imm(page, 6);
+        let word = self.b.ins().ishl_imm(word, 3);
+        let word = self.host_addr(block::CODE_PAGES.as_ptr() as u64, word);
+        let bits = self.b.ins().load(types::I64, MemFlags::trusted(), word, 0);
+        let bit = self.b.ins().band_imm(page, 63);
+        let bits = self.b.ins().ushr(bits, bit);
+        let bit = self.b.ins().band_imm(bits, 1);
+        let in_range = self.b.ins().uextend(types::I64, in_range);
+        let hit = self.b.ins().band(bit, in_range);
+
+        let mark = self.b.create_block();
+        let done = self.b.create_block();
+        self.b.ins().brif(hit, mark, &[], done, &[]);
+        self.b.switch_to_block(mark);
+        self.b.seal_block(mark);
+        let zero = self.b.ins().iconst(types::I64, 0);
+        let stale = self.host_addr(block::STALE.as_ptr() as u64, zero);
+        let one = self.b.ins().iconst(types::I8, 1);
+        self.b.ins().store(MemFlags::trusted(), one, stale, 0);
+        self.b.ins().jump(done, &[]);
+        self.b.switch_to_block(done);
+        self.b.seal_block(done);
+    }
+
+    /// A host pointer plus an I64 offset
+    fn host_addr(&mut self, base: u64, off: Value) -> Value {
+        let off = if self.ptr == types::I64 { off } else { self.b.ins().ireduce(self.ptr, off) };
+        self.b.ins().iadd_imm(off, base as i64)
+    }
+
+    /// Emit one instruction. Returns the next pc for the last one in a block.
+    fn inst(&mut self, inst: Instruction, pc: u64) -> Option<Value> {
+        use Instruction::*;
+        let sx = |imm: i32| imm as i64 as u64;
+        match inst {
+            Lw { rd, rs1, imm } | Ld { rd, rs1, imm } => {
+                let off = self.offset(rs1, imm);
+                let addr = self.host(off);
+                let v = if matches!(inst, Lw { .. }) {
+                    self.b.ins().sload32(MemFlags::new(), addr, 0)
+                } else {
+                    self.b.ins().load(types::I64, MemFlags::new(), addr, 0)
+                };
+                self.set(rd, v);
+            }
+            Sb { rs1, rs2, imm } | Sw { rs1, rs2, imm } | Sd { rs1, rs2, imm } => {
+                let off = self.offset(rs1, imm);
+                self.check_code_write(off);
+                let addr = self.host(off);
+                let v = self.get(rs2);
+                match inst {
+                    Sb { .. } => self.b.ins().istore8(MemFlags::new(), v, addr, 0),
+                    Sw { .. } => self.b.ins().istore32(MemFlags::new(), v, addr, 0),
+                    _ => self.b.ins().store(MemFlags::new(), v, addr, 0),
+                };
+            }
+            Addi { rd, rs1, imm } => {
+                let v = self.get(rs1);
+                let v = self.b.ins().iadd_imm(v, imm as i64);
+                self.set(rd, v);
+            }
+            Andi { rd, rs1, imm } => {
+                let v = self.get(rs1);
+                let v = self.b.ins().band_imm(v, imm as i64);
+                self.set(rd, v);
+            }
+            Slli { rd, rs1, shamt } => {
+                let v = self.get(rs1);
+                let v = self.b.ins().ishl_imm(v, shamt as i64);
+                self.set(rd, v);
+            }
+            Srli { rd, rs1, shamt } => {
+                let v = self.get(rs1);
+                let v = self.b.ins().ushr_imm(v, shamt as i64);
+                self.set(rd, v);
+            }
+            Addiw { rd, rs1, imm } => {
+                let v = self.get(rs1);
+                let v = self.b.ins().iadd_imm(v, imm as i64);
+                let v = self.sext32(v);
+                self.set(rd, v);
+            }
+            Slliw { rd, rs1, shamt } => {
+                let v = self.get(rs1);
+                let v = self.b.ins().ishl_imm(v, shamt as i64);
+                let v = self.sext32(v);
+                self.set(rd, v);
+            }
+            Add { rd, rs1, rs2 } | Sub { rd, rs1, rs2 } | Addw { rd, rs1, rs2 } | Subw { rd, rs1, rs2 } => {
+                let (x, y) = (self.get(rs1), self.get(rs2));
+                let v = match inst {
+                    Add { .. } | Addw { .. } => self.b.ins().iadd(x, y),
+                    _ => self.b.ins().isub(x, y),
+                };
+                let v = if matches!(inst, Addw { .. } | Subw { .. }) { self.sext32(v) } else { v };
+                self.set(rd, v);
+            }
+            Sll { rd, rs1, rs2 } => {
+                // Cranelift masks the shift amount to the type width, like RISC-V
+                let (x, y) = (self.get(rs1), self.get(rs2));
+                let v = self.b.ins().ishl(x, y);
+                self.set(rd, v);
+            }
+            Lui { rd, imm } => {
+                let v = self.b.ins().iconst(types::I64, imm as i64);
+                self.set(rd, v);
+            }
+            Auipc { rd, imm } => {
+                let v = self.b.ins().iconst(types::I64, pc.wrapping_add(sx(imm)) as i64);
+                self.set(rd, v);
+            }
+            Beq { rs1, rs2, imm } | Bne { rs1, rs2, imm } | Blt { rs1, rs2, imm } => {
+                let cc = match inst {
+                    Beq { .. } => IntCC::Equal,
+                    Bne { .. } => IntCC::NotEqual,
+                    _ => IntCC::SignedLessThan,
+                };
+                let (x, y) = (self.get(rs1), self.get(rs2));
+                let cond = self.b.ins().icmp(cc, x, y);
+                let taken = self.b.ins().iconst(types::I64, pc.wrapping_add(sx(imm)) as i64);
+                let next = self.b.ins().iconst(types::I64, (pc + 4) as i64);
+                return Some(self.b.ins().select(cond, taken, next));
+            }
+            Jal { rd, imm } => {
+                let link = self.b.ins().iconst(types::I64, (pc + 4) as i64);
+                self.set(rd, link);
+                return Some(self.b.ins().iconst(types::I64, pc.wrapping_add(sx(imm)) as i64));
+            }
+            Jalr { rd, rs1, imm } => {
+                let v = self.get(rs1);
+                let v = self.b.ins().iadd_imm(v, imm as i64);
+                let target = self.b.ins().band_imm(v, !1);
+                let link = self.b.ins().iconst(types::I64, (pc + 4) as i64);
+                self.set(rd, link);
+                return Some(target);
+            }
+            Fence | Csr => {}
+            _ => unreachable!(),
+        }
+        None
+    }
+}
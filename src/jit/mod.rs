@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// JIT tiers. Blocks the translation cache sees running often enough are
+// handed to a backend, which turns them into a host function taking the
+// guest registers and memory and returning the next pc. Blocks a backend
+// cannot handle (syscalls, anything unusual) just stay interpreted.
+
+#[cfg(feature = "cranelift")]
+mod cranelift;
+#[cfg(target_arch = "x86_64")]
+mod x86;
+
+use crate::decode::Instruction;
+use crate::utils::terminal_error;
+
+/// A compiled block: (registers, guest memory) -> next pc
+pub(crate) type NativeBlock = unsafe extern "C" fn(*mut u64, *mut libc::c_void) -> u64;
+
+/// Executions before a block gets compiled
+pub(crate) const HOT: u32 = 16;
+
+pub(crate) enum Jit {
+    #[cfg(feature = "cranelift")]
+    Cranelift(Box<cranelift::Compiler>),
+    #[cfg(target_arch = "x86_64")]
+    X86(x86::Compiler),
+}
+
+impl Jit {
+    /// Set up the backend named on the command line.
+    pub(crate) fn new(backend: &str) -> Self {
+        match backend {
+            #[cfg(target_arch = "x86_64")]
+            "native" | "x86-64" => Jit::X86(x86::Compiler::new()),
+            #[cfg(not(target_arch = "x86_64"))]
+            "native" => terminal_error("No native JIT backend for this host"),
+            #[cfg(feature = "cranelift")]
+            "cranelift" => Jit::Cranelift(Box::new(cranelift::Compiler::new())),
+            #[cfg(not(feature = "cranelift"))]
+            "cranelift" => terminal_error("riscv-um was built without the cranelift feature"),
+            _ => terminal_error(&format!("Unknown JIT backend {}", backend)),
+        }
+    }
+
+    /// Compile the block starting at pc, if the backend can.
+    pub(crate) fn compile(&mut self, pc: u64, insts: &[Instruction]) -> Option<NativeBlock> {
+        match *self {
+            #[cfg(feature = "cranelift")]
+            Jit::Cranelift(ref mut c) => c.compile(pc, insts),
+            #[cfg(target_arch = "x86_64")]
+            Jit::X86(ref mut c) => c.compile(pc, insts),
+        }
+    }
+
+    /// Forget all compiled code; the blocks using it are gone.
+    pub(crate) fn clear(&mut self) {
+        match *self {
+            #[cfg(feature = "cranelift")]
+            Jit::Cranelift(ref mut c) => c.clear(),
+            #[cfg(target_arch = "x86_64")]
+            Jit::X86(ref mut c) => c.clear(),
+        }
+    }
+}
@@ -0,0 +1,304 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Handwritten x86-64 backend. Guest registers stay in the register array
+// (rdi) and are loaded into rax/rcx/rdx around each instruction; guest
+// memory is addressed from rsi. No calls are made, so nothing needs saving
+// and the stack is left alone. Stores check the code page bitmap inline and
+// raise the stale flag exactly like the interpreter does.
+//
+// Only the System V calling convention is emitted, which is what
+// `extern "C"` means on the unix hosts we run on.
+
+use crate::block;
+use crate::decode::Instruction;
+use crate::GUARD_OFFSET;
+use crate::STACK_OFFSET;
+use crate::stack_bottom;
+use crate::stack_size;
+
+use super::NativeBlock;
+
+/// Size of the executable buffer; once full, blocks stay interpreted
+const CODE_SIZE: usize = 16 << 20;
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+
+pub(crate) struct Compiler {
+    code: *mut u8,
+    used: usize,
+}
+
+impl Compiler {
+    pub(crate) fn new() -> Self {
+        let code = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                CODE_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if code == libc::MAP_FAILED {
+            crate::utils::terminal_error("Unable to map JIT code buffer");
+        }
+        Self { code: code as *mut u8, used: 0 }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.used = 0;
+    }
+
+    pub(crate) fn compile(&mut self, pc: u64, insts: &[Instruction]) -> Option<NativeBlock> {
+        let mut a = Asm(Vec::new());
+        let mut pc = pc;
+        for inst in insts {
+            if !a.inst(*inst, pc) {
+                return None;
+            }
+            pc += 4;
+        }
+        // ran off the end of a page
+        if !insts.last()?.ends_block() {
+            a.movabs(RAX, pc);
+            a.ret();
+        }
+        let bytes = a.0;
+        if self.used + bytes.len() > CODE_SIZE {
+            return None;
+        }
+        unsafe {
+            let at = self.code.add(self.used);
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), at, bytes.len());
+            self.used += bytes.len().next_multiple_of(16);
+            Some(std::mem::transmute::<*mut u8, NativeBlock>(at))
+        }
+    }
+}
+
+struct Asm(Vec<u8>);
+
+impl Asm {
+    fn bytes(&mut self, b: &[u8]) {
+        self.0.extend_from_slice(b);
+    }
+
+    fn imm32(&mut self, v: i32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    /// mov reg, [rdi + 8*n]
+    fn load(&mut self, reg: u8, n: u8) {
+        self.bytes(&[0x48, 0x8b, 0x87 | reg << 3]);
+        self.imm32(n as i32 * 8);
+    }
+
+    /// mov [rdi + 8*n], reg, leaving x0 alone
+    fn store(&mut self, reg: u8, n: u8) {
+        if n != 0 {
+            self.bytes(&[0x48, 0x89, 0x87 | reg << 3]);
+            self.imm32(n as i32 * 8);
+        }
+    }
+
+    /// mov reg, imm64
+    fn movabs(&mut self, reg: u8, v: u64) {
+        self.bytes(&[0x48, 0xb8 + reg]);
+        self.bytes(&v.to_le_bytes());
+    }
+
+    /// add reg, simm32
+    fn add_imm(&mut self, reg: u8, v: i32) {
+        if v != 0 {
+            self.bytes(&[0x48, 0x81, 0xc0 | reg]);
+            self.imm32(v);
+        }
+    }
+
+    /// movsxd rax, eax
+    fn sext32(&mut self) {
+        self.bytes(&[0x48, 0x63, 0xc0]);
+    }
+
+    fn ret(&mut self) {
+        self.bytes(&[0xc3]);
+    }
+
+    /// Turn the guest address in rax into an offset into guest memory, as
+    /// offset() does. Clobbers rcx.
+    fn translate(&mut self, reg: u8, imm: i32) {
+        self.load(RAX, reg);
+        self.add_imm(RAX, imm);
+        // cmp rax, 1 << 24; jb done
+        self.bytes(&[0x48, 0x3d]);
+        self.imm32(1 << 24);
+        self.bytes(&[0x72, 40]);
+        self.movabs(RCX, stack_bottom);
+        // sub rax, rcx; cmp rax, stack_size; jb stack
+        self.bytes(&[0x48, 0x29, 0xc8, 0x48, 0x3d]);
+        self.imm32(stack_size as i32);
+        self.bytes(&[0x72, 12]);
+        self.movabs(RAX, GUARD_OFFSET);
+        // jmp done
+        self.bytes(&[0xeb, 7]);
+        // stack:
+        self.bytes(&[0x48, 0x81, 0xc0]);
+        self.imm32(STACK_OFFSET as i32);
+        // done:
+    }
+
+    /// Mark the cache stale if the offset in rax is on a code page.
+    /// Clobbers rcx and rdx.
+    fn check_code_write(&mut self) {
+        // mov rdx, rax; shr rdx, 12; cmp rdx, PAGES; jae done
+        self.bytes(&[0x48, 0x89, 0xc2, 0x48, 0xc1, 0xea, 12, 0x48, 0x81, 0xfa]);
+        self.imm32(block::PAGES as i32);
+        self.bytes(&[0x73, 29]);
+        self.movabs(RCX, block::CODE_PAGES.as_ptr() as u64);
+        // bt [rcx], rdx; jnc done
+        self.bytes(&[0x48, 0x0f, 0xa3, 0x11, 0x73, 13]);
+        self.movabs(RCX, block::STALE.as_ptr() as u64);
+        // mov byte [rcx], 1
+        self.bytes(&[0xc6, 0x01, 0x01]);
+    }
+
+    /// rax = guest pc to continue at, chosen by the flags from a cmp
+    fn branch(&mut self, cmov: u8, taken: u64, next: u64) {
+        self.movabs(RAX, next);
+        self.movabs(RDX, taken);
+        // cmovcc rax, rdx
+        self.bytes(&[0x48, 0x0f, cmov, 0xc2]);
+        self.ret();
+    }
+
+    /// Emit one instruction, or return false if it has to be interpreted.
+    fn inst(&mut self, inst: Instruction, pc: u64) -> bool {
+        use Instruction::*;
+        let sx = |imm: i32| imm as i64 as u64;
+        match inst {
+            Lw { rd, rs1, imm } => {
+                self.translate(rs1, imm);
+                // movsxd rax, dword [rsi + rax]
+                self.bytes(&[0x48, 0x63, 0x04, 0x06]);
+                self.store(RAX, rd);
+            }
+            Ld { rd, rs1, imm } => {
+                self.translate(rs1, imm);
+                // mov rax, [rsi + rax]
+                self.bytes(&[0x48, 0x8b, 0x04, 0x06]);
+                self.store(RAX, rd);
+            }
+            Sb { rs1, rs2, imm } | Sw { rs1, rs2, imm } | Sd { rs1, rs2, imm } => {
+                self.translate(rs1, imm);
+                self.check_code_write();
+                self.load(RCX, rs2);
+                match inst {
+                    // mov [rsi + rax], cl / ecx / rcx
+                    Sb { .. } => self.bytes(&[0x88, 0x0c, 0x06]),
+                    Sw { .. } => self.bytes(&[0x89, 0x0c, 0x06]),
+                    _ => self.bytes(&[0x48, 0x89, 0x0c, 0x06]),
+                }
+            }
+            Addi { rd, rs1, imm } => {
+                self.load(RAX, rs1);
+                self.add_imm(RAX, imm);
+                self.store(RAX, rd);
+            }
+            Andi { rd, rs1, imm } => {
+                self.load(RAX, rs1);
+                self.bytes(&[0x48, 0x25]);
+                self.imm32(imm);
+                self.store(RAX, rd);
+            }
+            Slli { rd, rs1, shamt } | Srli { rd, rs1, shamt } => {
+                self.load(RAX, rs1);
+                let op = if matches!(inst, Slli { .. }) { 0xe0 } else { 0xe8 };
+                self.bytes(&[0x48, 0xc1, op, shamt]);
+                self.store(RAX, rd);
+            }
+            Addiw { rd, rs1, imm } => {
+                self.load(RAX, rs1);
+                // add eax, imm32
+                self.bytes(&[0x05]);
+                self.imm32(imm);
+                self.sext32();
+                self.store(RAX, rd);
+            }
+            Slliw { rd, rs1, shamt } => {
+                self.load(RAX, rs1);
+                // shl eax, imm8
+                self.bytes(&[0xc1, 0xe0, shamt]);
+                self.sext32();
+                self.store(RAX, rd);
+            }
+            Add { rd, rs1, rs2 } | Sub { rd, rs1, rs2 } | Addw { rd, rs1, rs2 } | Subw { rd, rs1, rs2 } => {
+                self.load(RAX, rs1);
+                self.load(RCX, rs2);
+                match inst {
+                    // add/sub rax, rcx
+                    Add { .. } => self.bytes(&[0x48, 0x01, 0xc8]),
+                    Sub { .. } => self.bytes(&[0x48, 0x29, 0xc8]),
+                    // add/sub eax, ecx
+                    Addw { .. } => self.bytes(&[0x01, 0xc8]),
+                    _ => self.bytes(&[0x29, 0xc8]),
+                }
+                if matches!(inst, Addw { .. } | Subw { .. }) {
+                    self.sext32();
+                }
+                self.store(RAX, rd);
+            }
+            Sll { rd, rs1, rs2 } => {
+                self.load(RAX, rs1);
+                self.load(RCX, rs2);
+                // shl rax, cl
+                self.bytes(&[0x48, 0xd3, 0xe0]);
+                self.store(RAX, rd);
+            }
+            Lui { rd, imm } => {
+                // mov rax, simm32
+                self.bytes(&[0x48, 0xc7, 0xc0]);
+                self.imm32(imm);
+                self.store(RAX, rd);
+            }
+            Auipc { rd, imm } => {
+                self.movabs(RAX, pc.wrapping_add(sx(imm)));
+                self.store(RAX, rd);
+            }
+            Beq { rs1, rs2, imm } | Bne { rs1, rs2, imm } | Blt { rs1, rs2, imm } => {
+                self.load(RCX, rs1);
+                // cmp rcx, [rdi + 8*rs2]
+                self.bytes(&[0x48, 0x3b, 0x8f]);
+                self.imm32(rs2 as i32 * 8);
+                let cmov = match inst {
+                    Beq { .. } => 0x44,
+                    Bne { .. } => 0x45,
+                    _ => 0x4c,
+                };
+                self.branch(cmov, pc.wrapping_add(sx(imm)), pc + 4);
+            }
+            Jal { rd, imm } => {
+                self.movabs(RAX, pc + 4);
+                self.store(RAX, rd);
+                self.movabs(RAX, pc.wrapping_add(sx(imm)));
+                self.ret();
+            }
+            Jalr { rd, rs1, imm } => {
+                self.load(RAX, rs1);
+                self.add_imm(RAX, imm);
+                // and rax, -2
+                self.bytes(&[0x48, 0x83, 0xe0, 0xfe]);
+                self.movabs(RCX, pc + 4);
+                self.store(RCX, rd);
+                self.ret();
+            }
+            Fence | Csr => {}
+            // blocks are compiled from unfused instructions
+            LoadImm { .. } | Call { .. } | AuipcLd { .. } | Zext { .. } | BranchImm { .. } => return false,
+            FenceI | Ecall | Ebreak | Unimplemented(_) => return false,
+        }
+        true
+    }
+}
@@ -0,0 +1,36 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Limits on how long a guest may run (--max-insns, --timeout). The
+// instruction limit is counted by the translation cache; the timeout is a
+// watchdog thread raising a flag the cache checks between blocks. A guest
+// blocked in a system call never gets back to that check, so the watchdog
+// gives up on it after a grace period and exits by itself, still writing
+// out whatever was collected about the run.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Exit status when a limit stops the guest, as timeout(1) uses
+pub(crate) const EXIT_STATUS: i32 = 124;
+
+/// How long to wait for the guest to notice the timeout
+const GRACE: Duration = Duration::from_secs(1);
+
+static EXPIRED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn start_watchdog(timeout: Duration) {
+    std::thread::spawn(move || {
+        std::thread::sleep(timeout);
+        EXPIRED.store(true, Ordering::Relaxed);
+        std::thread::sleep(GRACE);
+        crate::trace::flush();
+        eprintln!("riscv-um: timeout after {:?}, guest stuck in a system call", timeout);
+        crate::exit(EXIT_STATUS);
+    });
+}
+
+#[inline(always)]
+pub(crate) fn expired() -> bool {
+    EXPIRED.load(Ordering::Relaxed)
+}
@@ -5,10 +5,28 @@
 #[cfg(not(target_endian = "little"))]
 compile_error!("Host architecture must be little endian");
 
+mod block;
+mod coverage;
+mod crash;
+mod debugger;
+mod decode;
+mod disasm;
+mod dwarf;
+mod gdb;
+mod interp;
+mod jit;
+mod limits;
 mod mm;
+mod profile;
+mod stack;
+mod stats;
+mod symbols;
+mod syscall;
+mod trace;
 mod utils;
 
 use clap::Parser;
+use clap::Subcommand;
 
 use utils::ConvertibleError;
 use utils::terminal_error;
@@ -33,10 +51,21 @@ along with this program. If not, see <https://www.gnu.org/licenses/>.";
 
 const stack_size: u64 = 1 << 20;
 const stack_bottom: u64 = (1 << 39) - stack_size;
+/// Where the stack lives in the host mapping: past program memory and a gap,
+/// so running off the end of program memory faults
+const STACK_OFFSET: u64 = 1 << 25;
+/// Where every other guest address is sent: never mapped, so the access
+/// faults (see offset)
+const GUARD_OFFSET: u64 = 1 << 37;
+/// Size of the host mapping, all of it reserved so that accesses running
+/// on from a translated address fault rather than reach host memory
+const GUEST_WINDOW: usize = 1 << 38;
 
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Display copyright and program information
     #[arg(short, long)]
     about: bool,
@@ -46,9 +75,83 @@ struct Args {
     // /// Set maximum program memory allocation (unimplemented)
     //#[arg(short, long)]
     //mem: Option<usize>,
+    /// Look up absolute guest paths in this directory first
+    #[arg(short = 'L', long)]
+    sysroot: Option<std::path::PathBuf>,
+    /// ISA string reported to the guest (AT_HWCAP, /proc/cpuinfo); the
+    /// default is what the decoder implements
+    #[arg(long, default_value = "rv64i_zicsr_zifencei")]
+    isa: String,
+    /// Log guest system calls to stderr
+    #[arg(long)]
+    strace: bool,
+    /// Syscall policy file restricting what the guest may do
+    #[arg(long)]
+    policy: Option<std::path::PathBuf>,
+    /// Record syscall results to this trace file
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<std::path::PathBuf>,
+    /// Replay syscall results from this trace file instead of running them
+    #[arg(long)]
+    replay: Option<std::path::PathBuf>,
+    /// Compile hot code to host code (backends: native, cranelift)
+    #[arg(long, value_name = "BACKEND", num_args = 0..=1, require_equals = true, default_missing_value = "native",
+          conflicts_with_all = ["gdb", "debug", "trace", "log_commits", "profile", "stats"])]
+    jit: Option<String>,
+    /// Stop the guest after this many instructions (exit status 124)
+    #[arg(long, value_name = "N", conflicts_with_all = ["gdb", "debug"])]
+    max_insns: Option<u64>,
+    /// Stop the guest after this many seconds (exit status 124)
+    #[arg(long, value_name = "SECONDS", conflicts_with_all = ["gdb", "debug"])]
+    timeout: Option<f64>,
+    /// Wait for gdb on this localhost port or Unix socket path, and run under
+    /// its control (plain interpreter only)
+    #[arg(long, value_name = "PORT|PATH")]
+    gdb: Option<String>,
+    /// Run under an interactive debugger prompt (plain interpreter only)
+    #[arg(long, conflicts_with = "gdb")]
+    debug: bool,
+    /// Log each executed instruction to stderr, or only those in FILTER:
+    /// comma-separated START-END address ranges and symbol names (plain
+    /// interpreter only)
+    #[arg(long, value_name = "FILTER", num_args = 0..=1, require_equals = true, default_missing_value = "",
+          conflicts_with_all = ["gdb", "debug"])]
+    trace: Option<String>,
+    /// Log each executed instruction to stderr in the format of Spike's
+    /// --log-commits (can be filtered with --trace=FILTER)
+    #[arg(long, conflicts_with_all = ["gdb", "debug"])]
+    log_commits: bool,
+    /// Write the basic blocks the guest ran to FILE in drcov format
+    /// (Lighthouse, bncov)
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["gdb", "debug", "trace", "log_commits"])]
+    drcov: Option<std::path::PathBuf>,
+    /// Write the source lines the guest ran to FILE in lcov format (needs a
+    /// guest with debug info)
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["gdb", "debug", "trace", "log_commits"])]
+    lcov: Option<std::path::PathBuf>,
+    /// Count the instructions each guest function and call stack retires,
+    /// write them to FILE as folded stacks (inferno, flamegraph.pl) and list
+    /// the busiest functions (plain interpreter only)
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["gdb", "debug", "trace", "log_commits", "drcov", "lcov"])]
+    profile: Option<std::path::PathBuf>,
+    /// At exit, print the instruction mix, branch, memory and syscall counts
+    /// and emulated MIPS to stderr, as a table or as JSON (plain interpreter
+    /// only)
+    #[arg(long, value_name = "FORMAT", num_args = 0..=1, require_equals = true, default_missing_value = "text",
+          value_parser = ["text", "json"],
+          conflicts_with_all = ["gdb", "debug", "trace", "log_commits", "drcov", "lcov", "profile"])]
+    stats: Option<String>,
     filename: Option<String>,
-    //#[arg(trailing_var_arg = true, allow_hyphen_values = true, hide = true)]
-    //args: Vec<String>
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true, hide = true)]
+    args: Vec<String>
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Disassemble the code in a RISC-V ELF file
+    Disasm {
+        file: std::path::PathBuf,
+    },
 }
 
 fn main() {
@@ -58,39 +161,56 @@ fn main() {
         println!("{}", ABOUT_MSG);
         std::process::exit(0);
     }
+    if let Some(Command::Disasm { file }) = args.command {
+        // a closed pipe just ends the listing
+        let _ = disasm::dump(&parse_elf(&read_elf(&file)));
+        std::process::exit(0);
+    }
     if args.filename.is_none() {
         terminal_error("No executable specified");
     }
-
-    // Load ELF
-    let path = std::path::PathBuf::from(&args.filename.unwrap());
-    if !path.exists() {
-        terminal_error("No such file or directory");
+    syscall::set_strace(args.strace);
+    if let Some(policy) = args.policy {
+        syscall::load_policy(&policy);
     }
-    let fc = std::fs::read(&path).e("Error reading file");
-    // check for ELF
-    if fc.len() < 4 || !(fc[0] == 0x7f && fc[1] == b'E' && fc[2] == b'L' && fc[3] == b'F') {
-        terminal_error("Non-ELF executables are currently not supported");
+    if let Some(trace) = args.record {
+        syscall::start_recording(&trace);
     }
-    let elf_f = elf::ElfBytes::<elf::endian::LittleEndian>::minimal_parse(&fc)
-        .e("Unable to parse ELF file");
-
-    // Check that the file is RISC-V 64, Linux
-    if elf_f.ehdr.class != elf::file::Class::ELF64 {
-        terminal_error("32-bit ELF files are not supported");
+    if let Some(trace) = args.replay {
+        syscall::start_replay(&trace);
     }
-    if elf_f.ehdr.osabi != elf::abi::ELFOSABI_SYSV {
-        terminal_error("File is not linked for Unix System V ABI");
+    if let Some(secs) = args.timeout {
+        let timeout = std::time::Duration::try_from_secs_f64(secs).unwrap_or_else(|_| terminal_error("Invalid timeout"));
+        limits::start_watchdog(timeout);
     }
-    if elf_f.ehdr.e_machine != elf::abi::EM_RISCV {
-        terminal_error("File architecture is not RISC-V");
+    if let Some(root) = args.sysroot {
+        if !root.is_dir() {
+            terminal_error("Sysroot is not a directory");
+        }
+        syscall::set_sysroot(std::fs::canonicalize(root).e("Unable to resolve sysroot"));
     }
 
+    // Load ELF
+    let filename = args.filename.unwrap();
+    let path = std::path::PathBuf::from(&filename);
+    let fc = read_elf(&path);
+    let elf_f = parse_elf(&fc);
+
     // Done this way for future development - cross-thread memory sharing
     //let mut mem = mm::MemoryMap::new();
     //let mut local_access = mem.clone();
     //let mut mema = mem.lock().unwrap();
-    let mema: *mut libc::c_void = unsafe {libc::mmap(0 as *mut libc::c_void, 1 << 24, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)};
+    // 16 MiB of program memory and the stack, in a reserved window that
+    // faults on everything else, so wild guest accesses are caught rather
+    // than hitting whatever the host has mapped nearby
+    let mema = unsafe {libc::mmap(0 as *mut libc::c_void, GUEST_WINDOW, libc::PROT_NONE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE, -1, 0)};
+    if mema == libc::MAP_FAILED {
+        terminal_error("Unable to reserve guest memory");
+    }
+    let rw = libc::PROT_READ | libc::PROT_WRITE;
+    if unsafe { libc::mprotect(mema, 1 << 24, rw) | libc::mprotect(adt(stack_bottom, mema), stack_size as usize, rw) } != 0 {
+        terminal_error("Unable to allocate guest memory");
+    }
     unsafe { libc::madvise(mema, 1 << 24, libc::MADV_HUGEPAGE); }
 
     // Load ELF data into memory; TODO make this faster, this memory system sucks
@@ -141,21 +261,9 @@ fn main() {
     for (i, b) in data.0.into_iter().enumerate() {
         mema.writebyte(entry_address + (i as u64), *b);
     }*/
-    let entry_address = 'L0: {
-        let data = elf_f
-            .symbol_table()
-            .e("Failed to get symbol table")
-            .ok_or(elf::parse::ParseError::UnexpectedSectionType((0, 0)))
-            .e("Symbol table is empty");
-        for sym in data.0 {
-            if let Ok(v) = data.1.get(sym.st_name as usize) {
-                if v == "_start" {
-                    break 'L0 sym.st_value;
-                }
-            }
-        }
-        terminal_error("Could not find _start")
-    };
+    let symbols = symbols::init(symbols::Symbols::load(&elf_f));
+    dwarf::load(&elf_f);
+    let entry_address = symbols.lookup("_start").unwrap_or_else(|| terminal_error("Could not find _start"));
 
     // allocate 2 MiB stack
     //mema.allocate_address_range((1 << 39) - stack_size, stack_size);
@@ -165,516 +273,181 @@ fn main() {
     registers[2] = 1 << 39;
     let mut pc: u64 = entry_address;
 
-    // command-line arguments
-    // TODO in a future version, not sure if this is even along spec
-    /*
-    registers[10] = args.args.len(); // argc
-    let argv_len = registers[10] << 3;
-    registers[11] -= argv_len; // argv
-    registers[2] -= argv_len;
-    for (i, arg) in args.args.reverse().into_iter().enumerate() {
-        let bytes = arg.bytes();
-        let bl = bytes.len();
-        registers[2] -= bl + 2;
-        for n in 0..bl+1 {
-            mema.writebyte(registers[2] + n, bytes[n]);
+    // Program headers are not part of any section, but the guest finds
+    // them through AT_PHDR, so map them where the kernel would
+    let mut regions: Vec<syscall::Region> = Vec::new();
+    let mut phdr_addr = 0;
+    let exe = std::fs::canonicalize(&path).e("Unable to resolve executable path");
+    if let Some(segs) = elf_f.segments() {
+        let phoff = elf_f.ehdr.e_phoff;
+        let phsize = (elf_f.ehdr.e_phnum as u64) * (elf_f.ehdr.e_phentsize as u64);
+        for seg in segs.iter().filter(|s| s.p_type == elf::abi::PT_LOAD) {
+            if phoff >= seg.p_offset && phoff + phsize <= seg.p_offset + seg.p_filesz {
+                phdr_addr = seg.p_vaddr + (phoff - seg.p_offset);
+                unsafe { libc::memcpy(adt(phdr_addr, mema), fc[phoff as usize..].as_ptr() as *const libc::c_void, phsize as usize); }
+            }
+            regions.push(syscall::Region {
+                start: seg.p_vaddr & !0xfff,
+                end: (seg.p_vaddr + seg.p_memsz + 0xfff) & !0xfff,
+                flags: seg.p_flags,
+                offset: seg.p_offset & !0xfff,
+                name: exe.to_string_lossy().into_owned(),
+            });
         }
-        mema.writedword((1 << 39) - (i << 3), registers[2]);
-    }*/
+    }
+    regions.push(syscall::Region {
+        start: stack_bottom,
+        end: 1 << 39,
+        flags: elf::abi::PF_R | elf::abi::PF_W,
+        offset: 0,
+        name: "[stack]".to_string(),
+    });
 
-    //println!("{:?}", args.args);
+    // command-line arguments, environment and auxiliary vector
+    let mut argv: Vec<Vec<u8>> = vec![filename.clone().into_bytes()];
+    argv.extend(args.args.into_iter().map(|a| a.into_bytes()));
+    let envp: Vec<Vec<u8>> = std::env::vars_os()
+        .map(|(k, v)| [k.as_encoded_bytes(), b"=", v.as_encoded_bytes()].concat())
+        .collect();
+    let auxv = vec![
+        (stack::AT_PHDR, phdr_addr),
+        (stack::AT_PHENT, elf_f.ehdr.e_phentsize as u64),
+        (stack::AT_PHNUM, elf_f.ehdr.e_phnum as u64),
+        (stack::AT_PAGESZ, 4096),
+        (stack::AT_BASE, 0),
+        (stack::AT_FLAGS, 0),
+        (stack::AT_ENTRY, entry_address),
+        (stack::AT_UID, unsafe { libc::getuid() } as u64),
+        (stack::AT_EUID, unsafe { libc::geteuid() } as u64),
+        (stack::AT_GID, unsafe { libc::getgid() } as u64),
+        (stack::AT_EGID, unsafe { libc::getegid() } as u64),
+        (stack::AT_HWCAP, stack::hwcap(&args.isa)),
+        (stack::AT_CLKTCK, 100),
+        (stack::AT_SECURE, 0),
+    ];
+    let (mut sp, auxv) = stack::init(mema, registers[2], filename.as_bytes(), &argv, &envp, auxv);
+    syscall::initial_stack(mema, &mut sp, registers[2]);
+    registers[2] = sp;
+    if args.drcov.is_some() || args.lcov.is_some() {
+        let image = || regions.iter().filter(|r| r.start != stack_bottom);
+        coverage::init(coverage::Config {
+            drcov: args.drcov,
+            lcov: args.lcov,
+            exe: exe.to_string_lossy().into_owned(),
+            base: image().map(|r| r.start).min().unwrap_or(0),
+            end: image().map(|r| r.end).max().unwrap_or(0),
+            code: image().filter(|r| r.flags & elf::abi::PF_X != 0).map(|r| (r.start, r.end)).collect(),
+        });
+    }
+    syscall::set_process(syscall::Process {
+        exe,
+        argv,
+        regions,
+        auxv,
+        isa: args.isa,
+    });
 
     // Main CPU loop
-    // TODO: factor opcode table out into separate file
-    let opcode_table: [Box<dyn Fn(u32, *mut libc::c_void, &mut [u64; 32], &mut u64) -> ()>; 128] = [
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|isn, mem, registers, pc| {
-            let fct = (isn & 0x00007000) >> 12;
-            let rd = ((isn & 0x0000_0f80) >> 7) as usize;
-            let rs = ((isn & 0x000f_8000) >> 15) as usize;
-            let imm = ((isn & 0xfff0_0000) >> 20) as u64;
-            let addr = registers[rs] + utils::sign_extend_12(imm);
-            match fct {
-                2 => {
-                        //println!("lw %{},0x${:x?} = {}", rd, addr, r);
-                        utils::write_register_safe(
-                            registers,
-                            rd,
-                            utils::sign_extend_32(unsafe {*(adt(addr, mem) as *const u32) as u64})
-                        );
-                }
-                3 => {
-                        //println!("ld %{},0x${:x?} = {}", rd, addr, r);
-                    utils::write_register_safe(registers, rd, unsafe {*(adt(addr, mem) as *const u64)});
-                }
-                _ => unimplemented!()
-            }
-            *pc += 4;
-        }),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|isn, _, registers, pc| {
-            // opcode = 0010011 - R-type (OP-IMM)
-            let imm = ((isn & 0xfff00000) >> 20) as u64;
-            let src = ((isn & 0x000f8000) >> 15) as usize;
-            let fct = (isn & 0x00007000) >> 12;
-            let dst = ((isn & 0x00000f80) >> 7) as usize;
-            let prefetch = registers[src];
-            match fct {
-                0 => {
-                    //println!("addi d%{},%{},0x{:x?} = 0x{:x?}", dst, src, utils::sign_extend_12(imm) as i64, prefetch + utils::sign_extend_12(imm));
-                    utils::write_register_safe(
-                        registers,
-                        dst,
-                        prefetch + utils::sign_extend_12(imm),
-                    );
-                }
-                1 => {
-                    let shamt = imm & 0x3f;
-                    //println!("slli d%{},%{},0x{:x?} = 0x{:x?}", dst, src, shamt, prefetch << shamt);
-                    match imm & 0xfc0 {
-                        0 => utils::write_register_safe(
-                            registers,
-                            dst,
-                            prefetch << shamt
-                        ),
-                        _ => unimplemented!()
-                    }
-                },
-                2 => unimplemented!(),
-                3 => unimplemented!(),
-                4 => unimplemented!(),
-                5 => {
-                    let shamt = imm & 0x3f;
-                    //println!("srli d%{},%{},0x{:x?} = 0x{:x?}", dst, src, shamt, prefetch >> shamt);
-                    match imm & 0xfc0 {
-                        0 => utils::write_register_safe(
-                            registers,
-                            dst,
-                            prefetch >> shamt
-                        ),
-                        _ => unimplemented!()
-                    }
-                },
-                6 => unimplemented!(),
-                7 => {
-                    let se = utils::sign_extend_12(imm);
-                    //println!("andi d%{},%{},0x{:x?} = 0x{:x?}", dst, src, se as i64, prefetch & se);
-                    utils::write_register_safe(
-                        registers,
-                        dst,
-                        prefetch & se
-                    );
-                },
-                _ => unimplemented!(),
-            }
-            *pc += 4;
-        }),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|isn, _, registers, pc| {
-            let rd = ((isn & 0x00000f80) >> 7) as usize;
-            let val = utils::sign_extend_32((isn & 0xfffff000) as u64);
-            //println!("auipc pc=0x{:x?},val={}", *pc, (val as i64));
-            utils::write_register_safe(
-                registers,
-                rd,
-                *pc + val
-            );
-            *pc += 4;
-        }),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|isn, _, registers, pc| {
-            let imm = ((isn & 0xfff00000) >> 20) as u64;
-            let src = ((isn & 0x000f8000) >> 15) as usize;
-            let fct = (isn & 0x00007000) >> 12;
-            let dst = ((isn & 0x00000f80) >> 7) as usize;
-            let prefetch = registers[src] as u32;
-            match fct {
-                0 => {
-                    //println!("addiw d%{},%{},0x{:x?} = 0x{:x?}", dst, src, imm, utils::sign_extend_32((prefetch + (imm as u32)) as u64));
-                    utils::write_register_safe(
-                        registers,
-                        dst,
-                        utils::sign_extend_32((prefetch + (utils::sign_extend_12(imm) as u32)) as u64),
-                    );
-                }
-                1 => {
-                    let shamt = imm & 0x1f;
-                    //println!("slliw d%{},%{},0x{:x?} = 0x{:x?}", dst, src, imm, ((prefetch << shamt) as u64));
-                    match imm & 0xfe0 {
-                        0 => utils::write_register_safe(
-                            registers,
-                            dst,
-                            (prefetch << shamt) as u64
-                        ),
-                        _ => unimplemented!()
-                    }
-                },
-                2 => unimplemented!(),
-                3 => unimplemented!(),
-                4 => unimplemented!(),
-                5 => unimplemented!(),
-                6 => unimplemented!(),
-                7 => unimplemented!(),
-                _ => unimplemented!(),
-            }
-            *pc += 4;
-        }),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|isn, mem, registers, pc| {
-            // opcode = 0100011 - S-type
-            let imm = ((((isn & 0xfe000000) >> 20) as u64) | (((isn & 0x00000f80) >> 7) as u64));
-            let pf = registers[((isn & 0x000f8000) >> 15) as usize];
-            let dst = imm + pf;
-            let src = ((isn & 0x01f00000) >> 20) as usize;
-            let fct = (isn & 0x00007000) >> 12;
-            match fct {
-                0 => {
-                    //println!("sb ${:x?},%{} = 0x{:x?}", dst, src, registers[src] as u8);
-                    unsafe {*(adt(dst, mem) as *mut u8) = registers[src] as u8};
-                    //if !mem.writebyte(dst, registers[src] as u8) {
-                    //    unsafe {
-                    //        libc::raise(11);
-                    //    }
-                    //}
-                },
-                1 => unimplemented!(),
-                2 => {
-                    //println!("sw ${:x?},%{} = 0x{:x?}", dst, src, registers[src] as u32);
-                    unsafe {*(adt(dst, mem) as *mut u32) = registers[src] as u32};
-                    /*for (i, n) in (registers[src] as u32).to_le_bytes().into_iter().enumerate() {
-                        if !mem.writebyte(dst + (i as u64), n) {
-                            unsafe {
-                                libc::raise(11);
-                            }
-                        }
-                    }*/
-                },
-                3 => {
-                    //println!("sd ${:x?},%{} = 0x{:x?}", dst, src, registers[src]);
-                    unsafe {*(adt(dst, mem) as *mut u64) = registers[src]};
-                    /*for (i, n) in registers[src].to_le_bytes().into_iter().enumerate() {
-                        if !mem.writebyte(dst + (i as u64), n) {
-                            unsafe {
-                                libc::raise(11);
-                            }
-                        }
-                    }*/
-                }
-                4 => unimplemented!(),
-                5 => unimplemented!(),
-                6 => unimplemented!(),
-                7 => unimplemented!(),
-                _ => unimplemented!(),
-            }
-            *pc += 4;
-        }),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|isn, _, registers, pc| {
-            let src1 = ((isn & 0x000f8000) >> 15) as usize;
-            let pf1 = registers[src1];
-            let src2 = ((isn & 0x01f00000) >> 20) as usize;
-            let mut pf2 = registers[src2];
-            let fct = (isn & 0x00007000) >> 12;
-            let dst = ((isn & 0x00000f80) >> 7) as usize;
-            match fct {
-                0 => {
-                    if isn & 0x4000_0000 != 0 {
-                        pf2 = 0 - pf2;
-                    }
-                    //println!("add/sub d%{},%{},%{} = 0x{:x?}", dst, src1, src2, pf1 + pf2);
-                    utils::write_register_safe(registers, dst, pf1 + pf2);
-                }
-                1 => {
-                    //println!("sll d%{},%{}={:x?},%{}=m{:x?} = 0x{:x?}", dst, src1, pf1, src2, pf2, pf1 << pf2);
-                    utils::write_register_safe(registers, dst, pf1 << pf2)
-                }
-                2 => unimplemented!(),
-                3 => unimplemented!(),
-                4 => unimplemented!(),
-                5 => unimplemented!(),
-                6 => unimplemented!(),
-                7 => unimplemented!(),
-                _ => unimplemented!(),
-            }
-            *pc += 4;
-        }),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|isn, _, registers, pc| {
-            let rd = ((isn & 0x00000f80) >> 7) as usize;
-            let val = (isn & 0xfffff000) as u64;
-            //println!("lui %{},0x{:x?}", rd, utils::sign_extend_32(val));
-            utils::write_register_safe(
-                registers,
-                rd,
-                utils::sign_extend_32(val),
-            );
-            *pc += 4;
-        }),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|isn, _, registers, pc| {
-            let src1 = ((isn & 0x000f8000) >> 15) as usize;
-            let pf1 = registers[src1];
-            let src2 = ((isn & 0x01f00000) >> 20) as usize;
-            let mut pf2 = registers[src2];
-            let fct = (isn & 0x00007000) >> 12;
-            let dst = ((isn & 0x00000f80) >> 7) as usize;
-            match fct {
-                0 => {
-                    if isn & 0x4000_0000 != 0 {
-                        pf2 = 0 - pf2;
-                    }
-                    //println!("addw/subw d%{},%{},%{} = 0x{:x?}", dst, src1, src2, pf1 as u32 + (pf2 as u32));
-                    utils::write_register_safe(registers, dst, utils::sign_extend_32(((pf1 as u32) + (pf2 as u32)) as u64));
-                }
-                1 => unimplemented!(),
-                2 => unimplemented!(),
-                3 => unimplemented!(),
-                4 => unimplemented!(),
-                5 => unimplemented!(),
-                6 => unimplemented!(),
-                7 => unimplemented!(),
-                _ => unimplemented!(),
-            }
-            *pc += 4;
-        }),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|isn, _, registers, pc| {
-            let imm = utils::sign_extend_13((((isn & 0x8000_0000) >> 19)
-                | ((isn & 0x7e00_0000) >> 20)
-                | ((isn & 0x0000_0f00) >> 7)
-                | ((isn & 0x0000_0080) << 4)) as u64);
-            let fct = (isn & 0x00007000) >> 12;
-            let rs2 = ((isn & 0x01f0_0000) >> 20) as usize;
-            let rs1 = ((isn & 0x000f_8000) >> 15) as usize;
-            let pf2 = registers[rs2];
-            let pf1 = registers[rs1];
-            //println!("branch {}, 1=%{}={:x?},2=%{}=0x{:x?}, imm={}, npc=0x{:x?}", fct, rs1, pf1, rs2, pf2, imm as i64, *pc + imm);
-            match fct {
-                0 => {
-                    if (pf1 == pf2) {
-                        *pc += imm;
-                        return;
-                    }
-                },
-                1 => {
-                    if (pf1 != pf2) {
-                        *pc += imm;
-                        return;
-                    }
-                },
-                2 => unimplemented!(),
-                3 => unimplemented!(),
-                4 => {
-                    if ((pf1 as i64) < (pf2 as i64)) {
-                        *pc += imm;
-                        return;
-                    }
-                },
-                5 => unimplemented!(),
-                6 => unimplemented!(),
-                7 => unimplemented!(),
-                _ => unimplemented!()
-            }
-            *pc += 4;
-        }),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|isn, _, registers, pc| {
-            let imm = ((isn & 0xfff00000) >> 20) as u64;
-            let src = ((isn & 0x000f8000) >> 15) as usize;
-            let fct = (isn & 0x00007000) >> 12;
-            let dst = ((isn & 0x00000f80) >> 7) as usize;
-            let prefetch = registers[src];
-            match fct {
-                0 => {
-                    //println!("jalr d%{},%{}=0x{:x?},{} = 0x{:x?}", dst, src, prefetch, utils::sign_extend_12(imm) as i64, prefetch + utils::sign_extend_12(imm));
-                    let target = (prefetch + utils::sign_extend_12(imm));
-                    if target & 1 != 0 {
-                        panic!("attempted invalid jalr");
-                    }
-                    utils::write_register_safe(registers, dst, *pc + 4);
-                    *pc = target;
-                    return;
-                }
-                _ => unimplemented!()
-            }
-            *pc += 4;
-        }),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|isn, _, registers, pc| {
-            // opcode = 1101111 - J-type
-            let rd = (isn & 0x00000f80) >> 7;
-            utils::write_register_safe(registers, rd as usize, *pc + 4);
-            let imm = ((isn & 0x8000_0000) >> 11)
-                | ((isn & 0x7fe0_0000) >> 20)
-                | ((isn & 0x0010_0000) >> 9)
-                | (isn & 0x000f_f000);
-            //println!("jal $0x{:x?},%{}", *pc + utils::sign_extend_21(imm as u64), rd);
-            *pc = *pc + utils::sign_extend_21(imm as u64);
-        }),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|isn, mem, registers, pc| {
-            // opcode = 1110011 - R-type (SYSTEM)
-            let imm = ((isn & 0xfff00000) >> 20) as u64;
-            let fct = (isn & 0x00007000) >> 12;
-            if fct == 0 {
-                if imm == 0 {
-                    //println!("ecall {}", registers[17]);
-                    // ECALL
-                    // TODO: probably should separate this into its own file
-                    // TODO: use a lookup table instead of match
-                    match registers[17] {
-                        64 => {
-                            let fd = registers[10];
-                            let ptr: *mut libc::c_void = adt(registers[11], mem);
-                            let n = registers[12];
-                            //let mut v: Vec<u8> = Vec::with_capacity(n as usize);
-                            /*for i in 0..n>>3 {
-                                if let Some(a) = mem.readdword(ptr+i<<3) {
-                                    v.extend_from_slice(&a.to_le_bytes());
-                                } else {
-                                    unsafe {libc::raise(11);}
-                                }
-                            }
-                            for i in ptr+(n & 0xffff_ffff_ffff_fff8)..ptr+n {
-                                if let Some(a) = mem.readbyte(i) {
-                                    v.push(a);
-                                } else {
-                                    unsafe {libc::raise(11);}
-                                }
-                            }*/
-                            unsafe {libc::write(fd as i32, ptr, n as usize);}
-                        }
-                        93 => std::process::exit(registers[10] as i32),
-                        _ => unimplemented!(),
-                    }
-                } else if imm == 1 {
-                    // EBREAK
-                    unimplemented!()
-                } else {
-                    unimplemented!()
-                }
+    crash::install(mema, &mut registers);
+    if let Some(target) = args.gdb {
+        gdb::serve(&target, mema, &mut registers, &mut pc);
+    }
+    if args.debug {
+        debugger::run(symbols, mema, &mut registers, &mut pc);
+    }
+    let format = if args.log_commits { trace::Format::Spike } else { trace::Format::Plain };
+    let max_insns = args.max_insns.unwrap_or(u64::MAX);
+    let (stop, retired) = if let Some(filter) = args.trace.or(args.log_commits.then(String::new)) {
+        let filter = trace::Filter::parse(&filter, symbols);
+        trace::run(&filter, format, symbols, mema, &mut registers, &mut pc, max_insns)
+    } else if let Some(path) = args.profile {
+        profile::run(path, mema, &mut registers, &mut pc, max_insns)
+    } else if let Some(format) = args.stats {
+        stats::run(format == "json", mema, &mut registers, &mut pc, max_insns)
+    } else {
+        let mut blocks = block::BlockCache::new(args.jit.as_deref().map(jit::Jit::new), args.max_insns);
+        // TODO split into threads for multiprocessing
+        loop {
+            // No compressed instruction support
+            match blocks.run(mema, &mut registers, &mut pc) {
+                block::Stop::Flushed => continue,
+                stop => break (stop, blocks.retired),
             }
-            *pc += 4;
-        }),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-        Box::new(|_, _, _, _| unimplemented!()),
-    ];
-    // TODO split into threads for multiprocessing
-    loop {
-        // No compressed instruction support
-        let isn = unsafe {*(adt(pc, mema) as *const u32)};
-        //println!("pc=0x{:x?}", pc);
-        opcode_table[isn as usize & 0x7f](isn, mema, &mut registers, &mut pc);
+        }
+    };
+    let why = match stop {
+        block::Stop::InsnLimit => "instruction limit reached",
+        block::Stop::Timeout => "timeout",
+        block::Stop::Flushed => unreachable!(),
+    };
+    eprintln!("riscv-um: {} after {} instructions", why, retired);
+    interp::dump_registers(&registers, pc);
+    exit(limits::EXIT_STATUS);
+}
+
+/// End the process for the guest, writing out what was collected about its
+/// run first.
+fn exit(status: i32) -> ! {
+    trace::flush();
+    coverage::write();
+    profile::write();
+    stats::write();
+    syscall::finish_recording();
+    std::process::exit(status);
+}
+
+fn read_elf(path: &std::path::Path) -> Vec<u8> {
+    if !path.exists() {
+        terminal_error("No such file or directory");
+    }
+    let fc = std::fs::read(path).e("Error reading file");
+    // check for ELF
+    if fc.len() < 4 || !(fc[0] == 0x7f && fc[1] == b'E' && fc[2] == b'L' && fc[3] == b'F') {
+        terminal_error("Non-ELF executables are currently not supported");
     }
+    fc
 }
 
+fn parse_elf(fc: &[u8]) -> elf::ElfBytes<'_, elf::endian::LittleEndian> {
+    let elf_f = elf::ElfBytes::<elf::endian::LittleEndian>::minimal_parse(fc)
+        .e("Unable to parse ELF file");
+
+    // Check that the file is RISC-V 64, Linux
+    if elf_f.ehdr.class != elf::file::Class::ELF64 {
+        terminal_error("32-bit ELF files are not supported");
+    }
+    if elf_f.ehdr.osabi != elf::abi::ELFOSABI_SYSV {
+        terminal_error("File is not linked for Unix System V ABI");
+    }
+    if elf_f.ehdr.e_machine != elf::abi::EM_RISCV {
+        terminal_error("File architecture is not RISC-V");
+    }
+    elf_f
+}
+
+/// Offset into the host mapping of guest address addr. Addresses outside
+/// program memory and the stack all go to GUARD_OFFSET.
 #[inline(always)]
-fn adt(addr: u64, mema: *mut libc::c_void) -> *mut libc::c_void {
-    (if addr & (1 << 38) != 0 {
-        unsafe {mema.byte_offset((addr - stack_bottom) as isize)}
+fn offset(addr: u64) -> u64 {
+    if addr < 1 << 24 {
+        addr
+    } else if addr.wrapping_sub(stack_bottom) < stack_size {
+        addr - stack_bottom + STACK_OFFSET
     } else {
-        unsafe {mema.byte_offset(addr as isize)}
-    }) as *mut libc::c_void
+        GUARD_OFFSET
+    }
+}
+
+#[inline(always)]
+fn adt(addr: u64, mema: *mut libc::c_void) -> *mut libc::c_void {
+    unsafe { mema.byte_add(offset(addr) as usize) }
+}
+
+/// Whether [addr, addr + len) lies in guest memory, for accesses the guest
+/// did not make itself (debuggers)
+fn mapped(addr: u64, len: u64) -> bool {
+    let Some(end) = addr.checked_add(len) else {
+        return false;
+    };
+    end <= 1 << 24 || (addr >= stack_bottom && end <= 1 << 39)
 }
@@ -0,0 +1,160 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Exact profiler (--profile). Runs the guest on the plain interpreter and
+// charges every retired instruction to the call stack it ran under, so the
+// counts are the same on every run. Calls and returns are recognised the way
+// the ABI hints them: jal/jalr linking ra or t0 is a call, jalr through ra or
+// t0 without linking is a return. Returns pop the shadow stack back to the
+// frame they return into, which copes with longjmp-style unwinding.
+//
+// At exit the call tree is written as folded stacks (one "a;b;c count" line
+// per stack, for inferno or flamegraph.pl) and the busiest functions are
+// listed on stderr.
+
+use std::collections::HashMap;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::block::Stop;
+use crate::decode::Instruction;
+use crate::interp;
+use crate::symbols;
+use crate::utils::ConvertibleError;
+
+/// Functions listed on stderr
+const TOP: usize = 20;
+
+struct Node {
+    /// Address the function was entered at
+    func: u64,
+    parent: usize,
+    children: Vec<(u64, usize)>,
+    /// Instructions retired in this function itself
+    count: u64,
+}
+
+struct Profile {
+    path: PathBuf,
+    /// The call tree; the root is the entry point
+    nodes: Vec<Node>,
+    /// Shadow stack of (caller's node, return address)
+    stack: Vec<(usize, u64)>,
+    current: usize,
+}
+
+/// The profile being taken, for writing it out on the way out of the
+/// process
+static PROFILE: interp::Shared<Profile> = interp::Shared::new();
+
+impl Profile {
+    fn call(&mut self, target: u64, ret: u64) {
+        let cur = self.current;
+        let child = match self.nodes[cur].children.iter().find(|c| c.0 == target) {
+            Some(&(_, n)) => n,
+            None => {
+                self.nodes.push(Node { func: target, parent: cur, children: Vec::new(), count: 0 });
+                let n = self.nodes.len() - 1;
+                self.nodes[cur].children.push((target, n));
+                n
+            }
+        };
+        self.stack.push((cur, ret));
+        self.current = child;
+    }
+
+    fn ret(&mut self, to: u64) {
+        if let Some(depth) = self.stack.iter().rposition(|f| f.1 == to) {
+            self.current = self.stack[depth].0;
+            self.stack.truncate(depth);
+        }
+    }
+
+    fn name(&self, func: u64) -> String {
+        match symbols::get().and_then(|s| s.describe(func)) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, off)) => format!("{}+{:#x}", name, off),
+            None => format!("{:#x}", func),
+        }
+    }
+
+    /// The functions from the root down to node n
+    fn path(&self, mut n: usize) -> Vec<u64> {
+        let mut funcs = vec![self.nodes[n].func];
+        while n != 0 {
+            n = self.nodes[n].parent;
+            funcs.push(self.nodes[n].func);
+        }
+        funcs.reverse();
+        funcs
+    }
+
+    fn write_folded(&self) -> std::io::Result<()> {
+        let mut out = BufWriter::new(std::fs::File::create(&self.path)?);
+        for (n, node) in self.nodes.iter().enumerate().filter(|(_, node)| node.count != 0) {
+            let names: Vec<String> = self.path(n).into_iter().map(|f| self.name(f)).collect();
+            writeln!(out, "{} {}", names.join(";"), node.count)?;
+        }
+        out.flush()
+    }
+
+    fn print_top(&self) {
+        // self: in the function itself; total: with everything it called,
+        // counting a recursive function once per stack
+        let mut totals: HashMap<u64, (u64, u64)> = HashMap::new();
+        for (n, node) in self.nodes.iter().enumerate().filter(|(_, node)| node.count != 0) {
+            totals.entry(node.func).or_default().0 += node.count;
+            let mut path = self.path(n);
+            path.sort_unstable();
+            path.dedup();
+            for f in path {
+                totals.entry(f).or_default().1 += node.count;
+            }
+        }
+        let all: u64 = self.nodes.iter().map(|n| n.count).sum();
+        let mut funcs: Vec<_> = totals.into_iter().collect();
+        funcs.sort_by_key(|&(f, (own, total))| (std::cmp::Reverse(own), std::cmp::Reverse(total), f));
+        let pct = |n: u64| n as f64 * 100.0 / all.max(1) as f64;
+        eprintln!("riscv-um: profile of {} instructions", all);
+        eprintln!("{:>14} {:>6} {:>14} {:>6}  function", "self", "", "total", "");
+        for (f, (own, total)) in funcs.into_iter().take(TOP) {
+            eprintln!("{:>14} {:>5.1}% {:>14} {:>5.1}%  {}", own, pct(own), total, pct(total), self.name(f));
+        }
+    }
+}
+
+/// Write out the profile, if one is being taken.
+pub(crate) fn write() {
+    PROFILE.with(|p| {
+        p.write_folded().e("Unable to write profile");
+        p.print_top();
+    });
+}
+
+impl interp::Hook for Profile {
+    fn before(&mut self, _: Instruction, _: u64, _: *mut libc::c_void, _: &[u64; 32]) {
+        self.nodes[self.current].count += 1;
+    }
+
+    fn after(&mut self, inst: Instruction, at: u64, _: *mut libc::c_void, _: &[u64; 32], pc: u64) {
+        match inst {
+            Instruction::Jal { rd: 1 | 5, .. } | Instruction::Jalr { rd: 1 | 5, .. } => self.call(pc, at + 4),
+            Instruction::Jalr { rd: 0, rs1: 1 | 5, .. } => self.ret(pc),
+            _ => {}
+        }
+    }
+}
+
+/// Run the guest under the profiler until a limit stops it. Returns why and
+/// how many instructions were retired.
+pub(crate) fn run(
+    path: PathBuf,
+    mem: *mut libc::c_void,
+    registers: &mut [u64; 32],
+    pc: &mut u64,
+    max_insns: u64,
+) -> (Stop, u64) {
+    let root = Node { func: *pc, parent: 0, children: Vec::new(), count: 0 };
+    PROFILE.install(Profile { path, nodes: vec![root], stack: Vec::new(), current: 0 });
+    interp::run(mem, registers, pc, max_insns, &mut &PROFILE)
+}
@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Initial process stack, laid out the way the Linux ELF loader does it:
+// argc at sp, followed by the argv, envp and auxv arrays, with the strings
+// they point to stored above them.
+
+use crate::adt;
+
+pub(crate) const AT_NULL: u64 = 0;
+pub(crate) const AT_PHDR: u64 = 3;
+pub(crate) const AT_PHENT: u64 = 4;
+pub(crate) const AT_PHNUM: u64 = 5;
+pub(crate) const AT_PAGESZ: u64 = 6;
+pub(crate) const AT_BASE: u64 = 7;
+pub(crate) const AT_FLAGS: u64 = 8;
+pub(crate) const AT_ENTRY: u64 = 9;
+pub(crate) const AT_UID: u64 = 11;
+pub(crate) const AT_EUID: u64 = 12;
+pub(crate) const AT_GID: u64 = 13;
+pub(crate) const AT_EGID: u64 = 14;
+pub(crate) const AT_HWCAP: u64 = 16;
+pub(crate) const AT_CLKTCK: u64 = 17;
+pub(crate) const AT_SECURE: u64 = 23;
+pub(crate) const AT_RANDOM: u64 = 25;
+pub(crate) const AT_EXECFN: u64 = 31;
+
+/// AT_HWCAP for an ISA string: one bit per single-letter extension.
+pub(crate) fn hwcap(isa: &str) -> u64 {
+    let exts = isa.strip_prefix("rv64").unwrap_or(isa);
+    let mut caps = 0;
+    for c in exts.bytes().take_while(|c| *c != b'_') {
+        match c {
+            b'g' => caps |= hwcap("imafd"),
+            b'a'..=b'z' => caps |= 1 << (c - b'a'),
+            _ => {}
+        }
+    }
+    caps
+}
+
+fn push(mem: *mut libc::c_void, sp: &mut u64, bytes: &[u8]) -> u64 {
+    *sp -= bytes.len() as u64;
+    unsafe {
+        libc::memcpy(adt(*sp, mem), bytes.as_ptr() as *const libc::c_void, bytes.len());
+    }
+    *sp
+}
+
+fn push_str(mem: *mut libc::c_void, sp: &mut u64, s: &[u8]) -> u64 {
+    push(mem, sp, &[0]);
+    push(mem, sp, s)
+}
+
+/// Build the stack below `top` and return the new sp together with the
+/// complete auxiliary vector, including the entries pointing into the stack.
+pub(crate) fn init(
+    mem: *mut libc::c_void,
+    top: u64,
+    execfn: &[u8],
+    argv: &[Vec<u8>],
+    envp: &[Vec<u8>],
+    mut auxv: Vec<(u64, u64)>,
+) -> (u64, Vec<(u64, u64)>) {
+    let mut sp = top;
+    let execfn_ptr = push_str(mem, &mut sp, execfn);
+    let argv_ptrs: Vec<u64> = argv.iter().map(|a| push_str(mem, &mut sp, a)).collect();
+    let envp_ptrs: Vec<u64> = envp.iter().map(|e| push_str(mem, &mut sp, e)).collect();
+    let mut random = [0u8; 16];
+    unsafe {
+        libc::getrandom(random.as_mut_ptr() as *mut libc::c_void, random.len(), 0);
+    }
+    let random_ptr = push(mem, &mut sp, &random);
+
+    auxv.push((AT_RANDOM, random_ptr));
+    auxv.push((AT_EXECFN, execfn_ptr));
+    auxv.push((AT_NULL, 0));
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend_from_slice(&argv_ptrs);
+    words.push(0);
+    words.extend_from_slice(&envp_ptrs);
+    words.push(0);
+    for (k, v) in auxv.iter() {
+        words.push(*k);
+        words.push(*v);
+    }
+    // sp must be 16-byte aligned once argc is on top
+    sp &= !0xf;
+    if words.len() & 1 != 0 {
+        sp -= 8;
+    }
+    for w in words.iter().rev() {
+        push(mem, &mut sp, &w.to_le_bytes());
+    }
+    (sp, auxv)
+}
@@ -0,0 +1,273 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Execution statistics (--stats). Runs the guest on the plain interpreter and
+// counts, per retired instruction, its opcode, whether a branch was taken,
+// the bytes it loaded or stored and the syscall it made. At exit the counts
+// are printed to stderr, as a table or as one line of JSON. The MIPS figure
+// is that of this counting interpreter, not of a normal run.
+
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use crate::adt;
+use crate::block::Stop;
+use crate::decode::Instruction;
+use crate::interp;
+use crate::syscall;
+
+/// Mnemonic and extension of each opcode counted, in the order they are
+/// listed
+const OPCODES: [(&str, &str); 35] = [
+    ("lui", "I"),
+    ("auipc", "I"),
+    ("jal", "I"),
+    ("jalr", "I"),
+    ("beq", "I"),
+    ("bne", "I"),
+    ("blt", "I"),
+    ("lw", "I"),
+    ("ld", "I"),
+    ("sb", "I"),
+    ("sw", "I"),
+    ("sd", "I"),
+    ("addi", "I"),
+    ("andi", "I"),
+    ("slli", "I"),
+    ("srli", "I"),
+    ("add", "I"),
+    ("sub", "I"),
+    ("sll", "I"),
+    ("addiw", "I"),
+    ("slliw", "I"),
+    ("addw", "I"),
+    ("subw", "I"),
+    ("fence", "I"),
+    ("ecall", "I"),
+    ("ebreak", "I"),
+    ("fence.i", "Zifencei"),
+    ("csrrw", "Zicsr"),
+    ("csrrs", "Zicsr"),
+    ("csrrc", "Zicsr"),
+    ("csrrwi", "Zicsr"),
+    ("csrrsi", "Zicsr"),
+    ("csrrci", "Zicsr"),
+    ("csr", "Zicsr"),
+    // cut short by the crash it causes
+    ("unknown", "unknown"),
+];
+
+/// Index into OPCODES of an instruction; the raw word tells the CSR
+/// instructions apart.
+fn opcode(inst: Instruction, word: u32) -> usize {
+    use Instruction::*;
+    match inst {
+        Lui { .. } => 0,
+        Auipc { .. } => 1,
+        Jal { .. } => 2,
+        Jalr { .. } => 3,
+        Beq { .. } => 4,
+        Bne { .. } => 5,
+        Blt { .. } => 6,
+        Lw { .. } => 7,
+        Ld { .. } => 8,
+        Sb { .. } => 9,
+        Sw { .. } => 10,
+        Sd { .. } => 11,
+        Addi { .. } => 12,
+        Andi { .. } => 13,
+        Slli { .. } => 14,
+        Srli { .. } => 15,
+        Add { .. } => 16,
+        Sub { .. } => 17,
+        Sll { .. } => 18,
+        Addiw { .. } => 19,
+        Slliw { .. } => 20,
+        Addw { .. } => 21,
+        Subw { .. } => 22,
+        Fence => 23,
+        Ecall => 24,
+        Ebreak => 25,
+        FenceI => 26,
+        Csr => match (word >> 12) & 7 {
+            f @ 1..=3 => 26 + f as usize,
+            f @ 5..=7 => 25 + f as usize,
+            _ => 33,
+        },
+        Unimplemented(_) => 34,
+        LoadImm { .. } | Call { .. } | AuipcLd { .. } | Zext { .. } | BranchImm { .. } => {
+            unreachable!("the per-instruction loop does not fuse")
+        }
+    }
+}
+
+struct Stats {
+    json: bool,
+    start: Instant,
+    opcodes: [u64; OPCODES.len()],
+    /// Taken branches, by opcode
+    taken: [u64; OPCODES.len()],
+    loads: u64,
+    load_bytes: u64,
+    stores: u64,
+    store_bytes: u64,
+    /// Calls made, by syscall number
+    syscalls: BTreeMap<u64, u64>,
+}
+
+/// The counts being taken, for printing them on the way out of the process
+static STATS: interp::Shared<Stats> = interp::Shared::new();
+
+fn syscall_name(nr: u64) -> String {
+    syscall::name(nr).map_or_else(|| format!("syscall_{}", nr), str::to_string)
+}
+
+impl Stats {
+    fn retired(&self) -> u64 {
+        self.opcodes.iter().sum()
+    }
+
+    fn extensions(&self) -> BTreeMap<&'static str, u64> {
+        let mut exts = BTreeMap::new();
+        for (n, (_, ext)) in self.opcodes.iter().zip(OPCODES) {
+            if *n != 0 {
+                *exts.entry(ext).or_default() += n;
+            }
+        }
+        exts
+    }
+
+    /// Conditional branches run: mnemonic, times run and times taken
+    fn branches(&self) -> Vec<(&'static str, u64, u64)> {
+        (4..=6).filter(|&i| self.opcodes[i] != 0).map(|i| (OPCODES[i].0, self.opcodes[i], self.taken[i])).collect()
+    }
+
+    fn print_text(&self, secs: f64, mips: f64) {
+        let all = self.retired();
+        let pct = |n: u64, of: u64| n as f64 * 100.0 / of.max(1) as f64;
+        eprintln!("riscv-um: {} instructions in {:.3}s ({:.1} MIPS)", all, secs, mips);
+        eprintln!("instructions:");
+        let mut ops: Vec<_> = self.opcodes.iter().zip(OPCODES).filter(|(n, _)| **n != 0).collect();
+        ops.sort_by_key(|(n, _)| std::cmp::Reverse(**n));
+        for (n, (name, _)) in ops {
+            eprintln!("  {:<10} {:>14} {:>5.1}%", name, n, pct(*n, all));
+        }
+        eprintln!("extensions:");
+        for (ext, n) in self.extensions() {
+            eprintln!("  {:<10} {:>14} {:>5.1}%", ext, n, pct(n, all));
+        }
+        let branches = self.branches();
+        if !branches.is_empty() {
+            eprintln!("branches:         run          taken     not taken");
+            let (mut run, mut taken) = (0, 0);
+            for (name, n, t) in branches {
+                eprintln!("  {:<10} {:>14} {:>8} {:>5.1}% {:>8} {:>5.1}%", name, n, t, pct(t, n), n - t, pct(n - t, n));
+                run += n;
+                taken += t;
+            }
+            eprintln!(
+                "  {:<10} {:>14} {:>8} {:>5.1}% {:>8} {:>5.1}%",
+                "all",
+                run,
+                taken,
+                pct(taken, run),
+                run - taken,
+                pct(run - taken, run)
+            );
+        }
+        eprintln!("memory:");
+        eprintln!("  {:<10} {:>14} {:>14} bytes", "loads", self.loads, self.load_bytes);
+        eprintln!("  {:<10} {:>14} {:>14} bytes", "stores", self.stores, self.store_bytes);
+        if !self.syscalls.is_empty() {
+            eprintln!("syscalls:");
+            for (nr, n) in self.syscalls.iter() {
+                eprintln!("  {:<16} {:>8}", syscall_name(*nr), n);
+            }
+        }
+    }
+
+    fn print_json(&self, secs: f64, mips: f64) {
+        let object = |fields: Vec<String>| format!("{{{}}}", fields.join(","));
+        let ops = self.opcodes.iter().zip(OPCODES).filter(|(n, _)| **n != 0);
+        let branches = self.branches().into_iter().map(|(name, n, t)| {
+            format!("\"{}\":{{\"taken\":{},\"not_taken\":{}}}", name, t, n - t)
+        });
+        let fields = vec![
+            format!("\"instructions\":{}", self.retired()),
+            format!("\"seconds\":{:.6}", secs),
+            format!("\"mips\":{:.3}", mips),
+            format!("\"opcodes\":{}", object(ops.map(|(n, (name, _))| format!("\"{}\":{}", name, n)).collect())),
+            format!(
+                "\"extensions\":{}",
+                object(self.extensions().into_iter().map(|(ext, n)| format!("\"{}\":{}", ext, n)).collect())
+            ),
+            format!("\"branches\":{}", object(branches.collect())),
+            format!("\"loads\":{{\"count\":{},\"bytes\":{}}}", self.loads, self.load_bytes),
+            format!("\"stores\":{{\"count\":{},\"bytes\":{}}}", self.stores, self.store_bytes),
+            format!(
+                "\"syscalls\":{}",
+                object(self.syscalls.iter().map(|(nr, n)| format!("\"{}\":{}", syscall_name(*nr), n)).collect())
+            ),
+        ];
+        eprintln!("{}", object(fields));
+    }
+}
+
+/// Print the statistics, if they are being collected.
+pub(crate) fn write() {
+    STATS.with(|s| {
+        let secs = s.start.elapsed().as_secs_f64();
+        let mips = s.retired() as f64 / secs.max(1e-9) / 1e6;
+        if s.json {
+            s.print_json(secs, mips);
+        } else {
+            s.print_text(secs, mips);
+        }
+    });
+}
+
+impl interp::Hook for Stats {
+    fn before(&mut self, inst: Instruction, at: u64, mem: *mut libc::c_void, registers: &[u64; 32]) {
+        let op = opcode(inst, unsafe { *(adt(at, mem) as *const u32) });
+        self.opcodes[op] += 1;
+        match interp::access(inst, registers) {
+            Some((_, len, false)) => {
+                self.loads += 1;
+                self.load_bytes += len;
+            }
+            Some((_, len, true)) => {
+                self.stores += 1;
+                self.store_bytes += len;
+            }
+            None => {}
+        }
+        if let Instruction::Ecall = inst {
+            *self.syscalls.entry(registers[17]).or_default() += 1;
+        }
+        if interp::taken(inst, registers) == Some(true) {
+            self.taken[op] += 1;
+        }
+    }
+}
+
+/// Run the guest, counting what it does, until a limit stops it. Returns
+/// why and how many instructions were retired.
+pub(crate) fn run(
+    json: bool,
+    mem: *mut libc::c_void,
+    registers: &mut [u64; 32],
+    pc: &mut u64,
+    max_insns: u64,
+) -> (Stop, u64) {
+    STATS.install(Stats {
+        json,
+        start: Instant::now(),
+        opcodes: [0; OPCODES.len()],
+        taken: [0; OPCODES.len()],
+        loads: 0,
+        load_bytes: 0,
+        stores: 0,
+        store_bytes: 0,
+        syscalls: BTreeMap::new(),
+    });
+    interp::run(mem, registers, pc, max_insns, &mut &STATS)
+}
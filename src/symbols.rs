@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// The guest's ELF symbol table, for finding _start and for showing
+// addresses as symbol+offset.
+
+use std::sync::OnceLock;
+
+use elf::ElfBytes;
+use elf::endian::LittleEndian;
+
+use crate::utils::ConvertibleError;
+
+pub(crate) struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+    /// Whether the size came from the symbol table rather than from where
+    /// the next symbol starts
+    pub sized: bool,
+}
+
+/// Named symbols, sorted by address
+pub(crate) struct Symbols(Vec<Symbol>);
+
+static SYMBOLS: OnceLock<Symbols> = OnceLock::new();
+
+/// Make the guest's symbols available to crash reports.
+pub(crate) fn init(symbols: Symbols) -> &'static Symbols {
+    SYMBOLS.get_or_init(|| symbols)
+}
+
+pub(crate) fn get() -> Option<&'static Symbols> {
+    SYMBOLS.get()
+}
+
+impl Symbols {
+    pub(crate) fn load(elf_f: &ElfBytes<LittleEndian>) -> Self {
+        let Some((syms, strtab)) = elf_f.symbol_table().e("Failed to get symbol table") else {
+            return Symbols(Vec::new());
+        };
+        let shdrs = elf_f.section_headers();
+        // the end of the section each symbol is in, for sizing labels
+        let mut out: Vec<(Symbol, Option<u64>)> = syms
+            .iter()
+            .filter(|s| {
+                !s.is_undefined()
+                    && !matches!(s.st_symtype(), elf::abi::STT_SECTION | elf::abi::STT_FILE | elf::abi::STT_TLS)
+            })
+            .filter_map(|s| {
+                let name = strtab.get(s.st_name as usize).ok()?;
+                // skip the assembler's local labels ($x, .L...)
+                if name.is_empty() || name.starts_with('$') || name.starts_with(".L") {
+                    return None;
+                }
+                let end = shdrs.and_then(|h| h.get(s.st_shndx as usize).ok()).map(|h| h.sh_addr + h.sh_size);
+                let sym = Symbol { name: name.to_string(), addr: s.st_value, size: s.st_size, sized: s.st_size != 0 };
+                Some((sym, end))
+            })
+            .collect();
+        out.sort_by_key(|s| s.0.addr);
+        // a label covers everything up to the next symbol in its section
+        for i in 0..out.len() {
+            if out[i].0.sized {
+                continue;
+            }
+            let addr = out[i].0.addr;
+            let next = out[i..].iter().map(|s| s.0.addr).find(|&a| a > addr);
+            let end = match (next, out[i].1) {
+                (Some(n), Some(e)) => n.min(e),
+                (n, e) => n.or(e).unwrap_or(addr),
+            };
+            out[i].0.size = end.saturating_sub(addr);
+        }
+        Symbols(out.into_iter().map(|s| s.0).collect())
+    }
+
+    pub(crate) fn lookup(&self, name: &str) -> Option<u64> {
+        self.0.iter().find(|s| s.name == name).map(|s| s.addr)
+    }
+
+    /// Names of the symbols starting at addr
+    pub(crate) fn at(&self, addr: u64) -> impl Iterator<Item = &str> {
+        let i = self.0.partition_point(|s| s.addr < addr);
+        self.0[i..].iter().take_while(move |s| s.addr == addr).map(|s| s.name.as_str())
+    }
+
+    /// The addresses [start, end) a symbol covers
+    pub(crate) fn extent(&self, name: &str) -> Option<(u64, u64)> {
+        let s = self.0.iter().find(|s| s.name == name)?;
+        Some((s.addr, s.addr + s.size.max(1)))
+    }
+
+    /// The symbol addr falls in, and the offset into it
+    pub(crate) fn describe(&self, addr: u64) -> Option<(&str, u64)> {
+        let i = self.0.partition_point(|s| s.addr <= addr).checked_sub(1)?;
+        let s = &self.0[i];
+        // prefer a symbol that has a size of its own over a label
+        let s = self.0[..=i].iter().rev().take_while(|t| t.addr == s.addr).find(|t| t.sized).unwrap_or(s);
+        if addr != s.addr && addr >= s.addr + s.size {
+            return None;
+        }
+        Some((&s.name, addr - s.addr))
+    }
+
+    /// addr as "name+0xoff", or just the number
+    pub(crate) fn format(&self, addr: u64) -> String {
+        match self.describe(addr) {
+            Some((name, 0)) => format!("{:#x} <{}>", addr, name),
+            Some((name, off)) => format!("{:#x} <{}+{:#x}>", addr, name, off),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
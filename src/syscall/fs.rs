@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Filesystem namespace calls. linux_dirent64 and the open flags have the same
+// layout on RISC-V and the supported hosts, so everything passes straight
+// through to the host kernel; raw syscalls are used where libc's wrapper
+// differs from the kernel ABI (getcwd returns a length, not a pointer).
+
+use super::guest_cstr;
+use super::host_result;
+use super::path::lookup;
+use super::procfs;
+use crate::adt;
+
+pub(super) fn getcwd(mem: *mut libc::c_void, buf: u64, size: u64) -> i64 {
+    host_result(unsafe { libc::syscall(libc::SYS_getcwd, adt(buf, mem), size as usize) })
+}
+
+pub(super) fn mkdirat(mem: *mut libc::c_void, dirfd: u64, path: u64, mode: u64) -> i64 {
+    let path = match guest_cstr(path, mem) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    host_result(unsafe { libc::mkdirat(dirfd as i32, path.as_ptr(), mode as libc::mode_t) } as i64)
+}
+
+pub(super) fn unlinkat(mem: *mut libc::c_void, dirfd: u64, path: u64, flags: u64) -> i64 {
+    let path = match guest_cstr(path, mem) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    host_result(unsafe { libc::unlinkat(dirfd as i32, path.as_ptr(), flags as i32) } as i64)
+}
+
+pub(super) fn faccessat2(mem: *mut libc::c_void, dirfd: u64, path: u64, mode: u64, flags: u64) -> i64 {
+    let path = match lookup(path, mem) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    host_result(unsafe {
+        libc::syscall(libc::SYS_faccessat2, dirfd as i32, path.as_ptr(), mode as i32, flags as i32)
+    })
+}
+
+pub(super) fn chdir(mem: *mut libc::c_void, path: u64) -> i64 {
+    let path = match lookup(path, mem) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    host_result(unsafe { libc::chdir(path.as_ptr()) } as i64)
+}
+
+pub(super) fn openat(mem: *mut libc::c_void, dirfd: u64, path: u64, flags: u64, mode: u64) -> i64 {
+    let path = match lookup(path, mem) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    if let Some(r) = procfs::open(path.guest(), flags, mode) {
+        return r;
+    }
+    host_result(unsafe { libc::openat(dirfd as i32, path.as_ptr(), flags as i32, mode as libc::c_uint) } as i64)
+}
+
+pub(super) fn getdents64(mem: *mut libc::c_void, fd: u64, dirp: u64, count: u64) -> i64 {
+    host_result(unsafe { libc::syscall(libc::SYS_getdents64, fd as i32, adt(dirp, mem), count as usize) })
+}
+
+pub(super) fn readlinkat(mem: *mut libc::c_void, dirfd: u64, path: u64, buf: u64, size: u64) -> i64 {
+    let path = match lookup(path, mem) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    if let Some(r) = procfs::readlink(path.guest(), adt(buf, mem), size) {
+        return r;
+    }
+    host_result(unsafe {
+        libc::readlinkat(
+            dirfd as i32,
+            path.as_ptr(),
+            adt(buf, mem) as *mut libc::c_char,
+            size as usize,
+        )
+    } as i64)
+}
+
+pub(super) fn renameat2(
+    mem: *mut libc::c_void,
+    olddirfd: u64,
+    oldpath: u64,
+    newdirfd: u64,
+    newpath: u64,
+    flags: u64,
+) -> i64 {
+    let (oldpath, newpath) = match (guest_cstr(oldpath, mem), guest_cstr(newpath, mem)) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err(e), _) | (_, Err(e)) => return e,
+    };
+    host_result(unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            olddirfd as i32,
+            oldpath.as_ptr(),
+            newdirfd as i32,
+            newpath.as_ptr(),
+            flags as u32,
+        )
+    })
+}
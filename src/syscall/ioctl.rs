@@ -0,0 +1,131 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// ioctl() requests, translated between the RISC-V kernel ABI and the host.
+// The guest sees the kernel's struct termios (19 control characters, no
+// speed fields); the host side goes through libc's termios, which has a
+// different size and layout, so it is converted field by field.
+
+use super::host_result;
+use crate::adt;
+
+const TCGETS: u64 = 0x5401;
+const TCSETS: u64 = 0x5402;
+const TCSETSW: u64 = 0x5403;
+const TCSETSF: u64 = 0x5404;
+const TIOCGPGRP: u64 = 0x540f;
+const TIOCSPGRP: u64 = 0x5410;
+const TIOCGWINSZ: u64 = 0x5413;
+const TIOCSWINSZ: u64 = 0x5414;
+const FIONREAD: u64 = 0x541b;
+const FIONBIO: u64 = 0x5421;
+const FIONCLEX: u64 = 0x5450;
+const FIOCLEX: u64 = 0x5451;
+
+const GUEST_NCCS: usize = 19;
+
+/// struct termios as seen by a RISC-V guest
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GuestTermios {
+    c_iflag: u32,
+    c_oflag: u32,
+    c_cflag: u32,
+    c_lflag: u32,
+    c_line: u8,
+    c_cc: [u8; GUEST_NCCS],
+}
+
+/// struct winsize as seen by a RISC-V guest
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GuestWinsize {
+    ws_row: u16,
+    ws_col: u16,
+    ws_xpixel: u16,
+    ws_ypixel: u16,
+}
+
+fn termios_to_guest(t: &libc::termios) -> GuestTermios {
+    let mut cc = [0u8; GUEST_NCCS];
+    let n = GUEST_NCCS.min(t.c_cc.len());
+    cc[..n].copy_from_slice(&t.c_cc[..n]);
+    GuestTermios {
+        c_iflag: t.c_iflag,
+        c_oflag: t.c_oflag,
+        c_cflag: t.c_cflag,
+        c_lflag: t.c_lflag,
+        c_line: t.c_line,
+        c_cc: cc,
+    }
+}
+
+fn termios_from_guest(g: &GuestTermios, t: &mut libc::termios) {
+    t.c_iflag = g.c_iflag;
+    t.c_oflag = g.c_oflag;
+    t.c_cflag = g.c_cflag;
+    t.c_lflag = g.c_lflag;
+    t.c_line = g.c_line;
+    let n = GUEST_NCCS.min(t.c_cc.len());
+    t.c_cc[..n].copy_from_slice(&g.c_cc[..n]);
+}
+
+fn tcsets(fd: i32, action: i32, gt: *const GuestTermios) -> i64 {
+    // Start from the current settings so host-only fields (speeds) survive
+    let mut t: libc::termios = unsafe { std::mem::zeroed() };
+    let r = unsafe { libc::tcgetattr(fd, &mut t) };
+    if r < 0 {
+        return host_result(r as i64);
+    }
+    termios_from_guest(unsafe { &*gt }, &mut t);
+    host_result(unsafe { libc::tcsetattr(fd, action, &t) } as i64)
+}
+
+pub(super) fn ioctl(mem: *mut libc::c_void, fd: u64, req: u64, arg: u64) -> i64 {
+    let fd = fd as i32;
+    let argp = adt(arg, mem);
+    match req {
+        TCGETS => {
+            let mut t: libc::termios = unsafe { std::mem::zeroed() };
+            let r = unsafe { libc::tcgetattr(fd, &mut t) };
+            if r == 0 {
+                unsafe { *(argp as *mut GuestTermios) = termios_to_guest(&t) };
+            }
+            host_result(r as i64)
+        }
+        TCSETS => tcsets(fd, libc::TCSANOW, argp as *const GuestTermios),
+        TCSETSW => tcsets(fd, libc::TCSADRAIN, argp as *const GuestTermios),
+        TCSETSF => tcsets(fd, libc::TCSAFLUSH, argp as *const GuestTermios),
+        TIOCGWINSZ => {
+            let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+            let r = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut ws) };
+            if r == 0 {
+                unsafe {
+                    *(argp as *mut GuestWinsize) = GuestWinsize {
+                        ws_row: ws.ws_row,
+                        ws_col: ws.ws_col,
+                        ws_xpixel: ws.ws_xpixel,
+                        ws_ypixel: ws.ws_ypixel,
+                    };
+                }
+            }
+            host_result(r as i64)
+        }
+        TIOCSWINSZ => {
+            let g = unsafe { *(argp as *const GuestWinsize) };
+            let ws = libc::winsize {
+                ws_row: g.ws_row,
+                ws_col: g.ws_col,
+                ws_xpixel: g.ws_xpixel,
+                ws_ypixel: g.ws_ypixel,
+            };
+            host_result(unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &ws) } as i64)
+        }
+        TIOCGPGRP => host_result(unsafe { libc::ioctl(fd, libc::TIOCGPGRP, argp as *mut libc::pid_t) } as i64),
+        TIOCSPGRP => host_result(unsafe { libc::ioctl(fd, libc::TIOCSPGRP, argp as *const libc::pid_t) } as i64),
+        FIONREAD => host_result(unsafe { libc::ioctl(fd, libc::FIONREAD, argp as *mut libc::c_int) } as i64),
+        FIONBIO => host_result(unsafe { libc::ioctl(fd, libc::FIONBIO, argp as *const libc::c_int) } as i64),
+        FIONCLEX => host_result(unsafe { libc::ioctl(fd, libc::FIONCLEX) } as i64),
+        FIOCLEX => host_result(unsafe { libc::ioctl(fd, libc::FIOCLEX) } as i64),
+        _ => -(libc::ENOTTY as i64),
+    }
+}
@@ -0,0 +1,200 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Linux system call emulation. RISC-V uses the asm-generic syscall table,
+// so only the *at() variants of the path calls exist.
+
+mod fs;
+mod ioctl;
+mod net;
+mod path;
+mod policy;
+mod poll;
+mod procfs;
+mod replay;
+mod strace;
+
+pub(crate) use path::set_sysroot;
+pub(crate) use policy::load_policy;
+pub(crate) use procfs::Process;
+pub(crate) use procfs::Region;
+pub(crate) use procfs::set_process;
+pub(crate) use replay::finish_recording;
+pub(crate) use replay::initial_stack;
+pub(crate) use replay::start_recording;
+pub(crate) use replay::start_replay;
+pub(crate) use strace::name;
+pub(crate) use strace::set_strace;
+
+use std::ffi::CString;
+
+use crate::adt;
+use crate::mapped;
+
+pub(crate) const SYS_GETCWD: u64 = 17;
+pub(crate) const SYS_EVENTFD2: u64 = 19;
+pub(crate) const SYS_EPOLL_CREATE1: u64 = 20;
+pub(crate) const SYS_EPOLL_CTL: u64 = 21;
+pub(crate) const SYS_EPOLL_PWAIT: u64 = 22;
+pub(crate) const SYS_IOCTL: u64 = 29;
+pub(crate) const SYS_MKDIRAT: u64 = 34;
+pub(crate) const SYS_UNLINKAT: u64 = 35;
+pub(crate) const SYS_FACCESSAT: u64 = 48;
+pub(crate) const SYS_CHDIR: u64 = 49;
+pub(crate) const SYS_FCHDIR: u64 = 50;
+pub(crate) const SYS_OPENAT: u64 = 56;
+pub(crate) const SYS_CLOSE: u64 = 57;
+pub(crate) const SYS_GETDENTS64: u64 = 61;
+pub(crate) const SYS_READ: u64 = 63;
+pub(crate) const SYS_WRITE: u64 = 64;
+pub(crate) const SYS_PSELECT6: u64 = 72;
+pub(crate) const SYS_PPOLL: u64 = 73;
+pub(crate) const SYS_READLINKAT: u64 = 78;
+pub(crate) const SYS_TIMERFD_CREATE: u64 = 85;
+pub(crate) const SYS_TIMERFD_SETTIME: u64 = 86;
+pub(crate) const SYS_TIMERFD_GETTIME: u64 = 87;
+pub(crate) const SYS_EXIT: u64 = 93;
+pub(crate) const SYS_EXIT_GROUP: u64 = 94;
+pub(crate) const SYS_SOCKET: u64 = 198;
+pub(crate) const SYS_SOCKETPAIR: u64 = 199;
+pub(crate) const SYS_BIND: u64 = 200;
+pub(crate) const SYS_LISTEN: u64 = 201;
+pub(crate) const SYS_ACCEPT: u64 = 202;
+pub(crate) const SYS_CONNECT: u64 = 203;
+pub(crate) const SYS_GETSOCKNAME: u64 = 204;
+pub(crate) const SYS_GETPEERNAME: u64 = 205;
+pub(crate) const SYS_SENDTO: u64 = 206;
+pub(crate) const SYS_RECVFROM: u64 = 207;
+pub(crate) const SYS_SETSOCKOPT: u64 = 208;
+pub(crate) const SYS_GETSOCKOPT: u64 = 209;
+pub(crate) const SYS_SHUTDOWN: u64 = 210;
+pub(crate) const SYS_SENDMSG: u64 = 211;
+pub(crate) const SYS_RECVMSG: u64 = 212;
+pub(crate) const SYS_ACCEPT4: u64 = 242;
+pub(crate) const SYS_RENAMEAT2: u64 = 276;
+pub(crate) const SYS_FACCESSAT2: u64 = 439;
+pub(crate) const SYS_EPOLL_PWAIT2: u64 = 441;
+
+/// Handle an ECALL: number in a7, arguments in a0-a5, result back in a0.
+pub(crate) fn ecall(registers: &mut [u64; 32], mem: *mut libc::c_void) {
+    let a = [
+        registers[10],
+        registers[11],
+        registers[12],
+        registers[13],
+        registers[14],
+        registers[15],
+    ];
+    let nr = registers[17];
+    let recorded = if replay::active() { replay::replay(nr, &a, mem) } else { None };
+    let verdict = policy::check(nr, &a, mem);
+    let trace = strace::enabled() || verdict.log;
+    if trace {
+        strace::enter(nr, &a, mem);
+    }
+    let ret = match recorded.or(verdict.ret) {
+        Some(r) => r,
+        None => {
+            if is_exit(nr) && replay::active() {
+                // exit never returns, so save it beforehand
+                replay::record(nr, &a, 0, mem);
+            }
+            dispatch(nr, &a, mem)
+        }
+    };
+    if trace {
+        strace::exit(ret);
+    }
+    if replay::active() {
+        replay::record(nr, &a, ret, mem);
+    }
+    registers[10] = ret as u64;
+}
+
+fn dispatch(nr: u64, a: &[u64; 6], mem: *mut libc::c_void) -> i64 {
+    // TODO: use a lookup table instead of match
+    match nr {
+        SYS_GETCWD => fs::getcwd(mem, a[0], a[1]),
+        SYS_EVENTFD2 => poll::eventfd2(a[0], a[1]),
+        SYS_EPOLL_CREATE1 => poll::epoll_create1(a[0]),
+        SYS_EPOLL_CTL => poll::epoll_ctl(mem, a[0], a[1], a[2], a[3]),
+        SYS_EPOLL_PWAIT => poll::epoll_pwait(mem, a[0], a[1], a[2], a[3], a[4], a[5]),
+        SYS_IOCTL => ioctl::ioctl(mem, a[0], a[1], a[2]),
+        SYS_MKDIRAT => fs::mkdirat(mem, a[0], a[1], a[2]),
+        SYS_UNLINKAT => fs::unlinkat(mem, a[0], a[1], a[2]),
+        SYS_FACCESSAT => fs::faccessat2(mem, a[0], a[1], a[2], 0),
+        SYS_CHDIR => fs::chdir(mem, a[0]),
+        SYS_FCHDIR => host_result(unsafe { libc::fchdir(a[0] as i32) } as i64),
+        SYS_OPENAT => fs::openat(mem, a[0], a[1], a[2], a[3]),
+        SYS_CLOSE => host_result(unsafe { libc::close(a[0] as i32) } as i64),
+        SYS_GETDENTS64 => fs::getdents64(mem, a[0], a[1], a[2]),
+        SYS_READ => host_result(unsafe { libc::read(a[0] as i32, adt(a[1], mem), a[2] as usize) } as i64),
+        SYS_WRITE => host_result(unsafe { libc::write(a[0] as i32, adt(a[1], mem), a[2] as usize) } as i64),
+        SYS_PSELECT6 => poll::pselect6(mem, a[0], a[1], a[2], a[3], a[4], a[5]),
+        SYS_PPOLL => poll::ppoll(mem, a[0], a[1], a[2], a[3], a[4]),
+        SYS_READLINKAT => fs::readlinkat(mem, a[0], a[1], a[2], a[3]),
+        SYS_TIMERFD_CREATE => poll::timerfd_create(a[0], a[1]),
+        SYS_TIMERFD_SETTIME => poll::timerfd_settime(mem, a[0], a[1], a[2], a[3]),
+        SYS_TIMERFD_GETTIME => poll::timerfd_gettime(mem, a[0], a[1]),
+        SYS_EXIT | SYS_EXIT_GROUP => crate::exit(a[0] as i32),
+        SYS_SOCKET => net::socket(a[0], a[1], a[2]),
+        SYS_SOCKETPAIR => net::socketpair(mem, a[0], a[1], a[2], a[3]),
+        SYS_BIND => net::bind(mem, a[0], a[1], a[2]),
+        SYS_LISTEN => net::listen(a[0], a[1]),
+        SYS_ACCEPT => net::accept4(mem, a[0], a[1], a[2], 0),
+        SYS_CONNECT => net::connect(mem, a[0], a[1], a[2]),
+        SYS_GETSOCKNAME => net::getsockname(mem, a[0], a[1], a[2]),
+        SYS_GETPEERNAME => net::getpeername(mem, a[0], a[1], a[2]),
+        SYS_SENDTO => net::sendto(mem, a[0], a[1], a[2], a[3], a[4], a[5]),
+        SYS_RECVFROM => net::recvfrom(mem, a[0], a[1], a[2], a[3], a[4], a[5]),
+        SYS_SETSOCKOPT => net::setsockopt(mem, a[0], a[1], a[2], a[3], a[4]),
+        SYS_GETSOCKOPT => net::getsockopt(mem, a[0], a[1], a[2], a[3], a[4]),
+        SYS_SHUTDOWN => net::shutdown(a[0], a[1]),
+        SYS_SENDMSG => net::sendmsg(mem, a[0], a[1], a[2]),
+        SYS_RECVMSG => net::recvmsg(mem, a[0], a[1], a[2]),
+        SYS_ACCEPT4 => net::accept4(mem, a[0], a[1], a[2], a[3]),
+        SYS_RENAMEAT2 => fs::renameat2(mem, a[0], a[1], a[2], a[3], a[4]),
+        SYS_FACCESSAT2 => fs::faccessat2(mem, a[0], a[1], a[2], a[3]),
+        SYS_EPOLL_PWAIT2 => poll::epoll_pwait2(mem, a[0], a[1], a[2], a[3], a[4], a[5]),
+        _ => -(libc::ENOSYS as i64),
+    }
+}
+
+/// Whether nr ends the process (there is only one thread, so exit and
+/// exit_group are the same).
+pub(crate) fn is_exit(nr: u64) -> bool {
+    nr == SYS_EXIT || nr == SYS_EXIT_GROUP
+}
+
+/// Convert a host libc return value into the kernel convention of
+/// returning -errno on failure.
+#[inline(always)]
+pub(crate) fn host_result(r: i64) -> i64 {
+    if r < 0 {
+        -(unsafe { *libc::__errno_location() } as i64)
+    } else {
+        r
+    }
+}
+
+/// Guest pointer for an optional argument, keeping NULL as NULL.
+#[inline(always)]
+pub(crate) fn guest_ptr(addr: u64, mem: *mut libc::c_void) -> *mut libc::c_void {
+    if addr == 0 { std::ptr::null_mut() } else { adt(addr, mem) }
+}
+
+/// Copy in the NUL-terminated string at guest address addr, the way the
+/// kernel copies in paths: -EFAULT if it runs out of guest memory first,
+/// -ENAMETOOLONG if it is longer than PATH_MAX.
+pub(crate) fn guest_cstr(addr: u64, mem: *mut libc::c_void) -> Result<CString, i64> {
+    let mut bytes = Vec::new();
+    for i in 0..libc::PATH_MAX as u64 {
+        if !mapped(addr, i + 1) {
+            return Err(-(libc::EFAULT as i64));
+        }
+        match unsafe { *(adt(addr + i, mem) as *const u8) } {
+            0 => return Ok(CString::new(bytes).unwrap()),
+            b => bytes.push(b),
+        }
+    }
+    Err(-(libc::ENAMETOOLONG as i64))
+}
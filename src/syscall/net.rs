@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Socket calls. sockaddr, socket option values and cmsghdr have the same
+// layout for RISC-V and the supported hosts, and guest descriptors are host
+// descriptors, so those pass straight through (SCM_RIGHTS included). msghdr
+// and its iovec array carry guest pointers and are rebuilt on the host side.
+
+use super::guest_ptr;
+use super::host_result;
+use crate::adt;
+
+/// struct msghdr as seen by a RISC-V guest
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GuestMsghdr {
+    msg_name: u64,
+    msg_namelen: u32,
+    msg_iov: u64,
+    msg_iovlen: u64,
+    msg_control: u64,
+    msg_controllen: u64,
+    msg_flags: i32,
+}
+
+/// struct iovec as seen by a RISC-V guest
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GuestIovec {
+    iov_base: u64,
+    iov_len: u64,
+}
+
+// Linux caps iovec arrays at UIO_MAXIOV
+const UIO_MAXIOV: u64 = 1024;
+
+fn addr_ptr(addr: u64, mem: *mut libc::c_void) -> *mut libc::sockaddr {
+    guest_ptr(addr, mem) as *mut libc::sockaddr
+}
+
+fn len_ptr(len: u64, mem: *mut libc::c_void) -> *mut libc::socklen_t {
+    guest_ptr(len, mem) as *mut libc::socklen_t
+}
+
+pub(super) fn socket(domain: u64, ty: u64, protocol: u64) -> i64 {
+    host_result(unsafe { libc::socket(domain as i32, ty as i32, protocol as i32) } as i64)
+}
+
+pub(super) fn socketpair(mem: *mut libc::c_void, domain: u64, ty: u64, protocol: u64, sv: u64) -> i64 {
+    host_result(unsafe {
+        libc::socketpair(domain as i32, ty as i32, protocol as i32, adt(sv, mem) as *mut libc::c_int)
+    } as i64)
+}
+
+pub(super) fn bind(mem: *mut libc::c_void, fd: u64, addr: u64, len: u64) -> i64 {
+    host_result(unsafe { libc::bind(fd as i32, addr_ptr(addr, mem), len as libc::socklen_t) } as i64)
+}
+
+pub(super) fn listen(fd: u64, backlog: u64) -> i64 {
+    host_result(unsafe { libc::listen(fd as i32, backlog as i32) } as i64)
+}
+
+pub(super) fn accept4(mem: *mut libc::c_void, fd: u64, addr: u64, len: u64, flags: u64) -> i64 {
+    host_result(unsafe { libc::accept4(fd as i32, addr_ptr(addr, mem), len_ptr(len, mem), flags as i32) } as i64)
+}
+
+pub(super) fn connect(mem: *mut libc::c_void, fd: u64, addr: u64, len: u64) -> i64 {
+    host_result(unsafe { libc::connect(fd as i32, addr_ptr(addr, mem), len as libc::socklen_t) } as i64)
+}
+
+pub(super) fn getsockname(mem: *mut libc::c_void, fd: u64, addr: u64, len: u64) -> i64 {
+    host_result(unsafe { libc::getsockname(fd as i32, addr_ptr(addr, mem), len_ptr(len, mem)) } as i64)
+}
+
+pub(super) fn getpeername(mem: *mut libc::c_void, fd: u64, addr: u64, len: u64) -> i64 {
+    host_result(unsafe { libc::getpeername(fd as i32, addr_ptr(addr, mem), len_ptr(len, mem)) } as i64)
+}
+
+pub(super) fn sendto(mem: *mut libc::c_void, fd: u64, buf: u64, len: u64, flags: u64, addr: u64, alen: u64) -> i64 {
+    host_result(unsafe {
+        libc::sendto(
+            fd as i32,
+            adt(buf, mem),
+            len as usize,
+            flags as i32,
+            addr_ptr(addr, mem),
+            alen as libc::socklen_t,
+        )
+    } as i64)
+}
+
+pub(super) fn recvfrom(mem: *mut libc::c_void, fd: u64, buf: u64, len: u64, flags: u64, addr: u64, alen: u64) -> i64 {
+    host_result(unsafe {
+        libc::recvfrom(
+            fd as i32,
+            adt(buf, mem),
+            len as usize,
+            flags as i32,
+            addr_ptr(addr, mem),
+            len_ptr(alen, mem),
+        )
+    } as i64)
+}
+
+pub(super) fn setsockopt(mem: *mut libc::c_void, fd: u64, level: u64, name: u64, val: u64, len: u64) -> i64 {
+    host_result(unsafe {
+        libc::setsockopt(fd as i32, level as i32, name as i32, guest_ptr(val, mem), len as libc::socklen_t)
+    } as i64)
+}
+
+pub(super) fn getsockopt(mem: *mut libc::c_void, fd: u64, level: u64, name: u64, val: u64, len: u64) -> i64 {
+    host_result(unsafe {
+        libc::getsockopt(fd as i32, level as i32, name as i32, guest_ptr(val, mem), len_ptr(len, mem))
+    } as i64)
+}
+
+pub(super) fn shutdown(fd: u64, how: u64) -> i64 {
+    host_result(unsafe { libc::shutdown(fd as i32, how as i32) } as i64)
+}
+
+/// Build a host msghdr from the guest's. The iovec array backing it is
+/// returned alongside and must outlive the call.
+fn msghdr_from_guest(mem: *mut libc::c_void, g: &GuestMsghdr) -> Option<(libc::msghdr, Vec<libc::iovec>)> {
+    if g.msg_iovlen > UIO_MAXIOV {
+        return None;
+    }
+    let giov = adt(g.msg_iov, mem) as *const GuestIovec;
+    let mut iov: Vec<libc::iovec> = (0..g.msg_iovlen as usize)
+        .map(|i| {
+            let v = unsafe { *giov.add(i) };
+            libc::iovec {
+                iov_base: adt(v.iov_base, mem),
+                iov_len: v.iov_len as usize,
+            }
+        })
+        .collect();
+    let mut h: libc::msghdr = unsafe { std::mem::zeroed() };
+    h.msg_name = guest_ptr(g.msg_name, mem);
+    h.msg_namelen = g.msg_namelen;
+    h.msg_iov = iov.as_mut_ptr();
+    h.msg_iovlen = iov.len();
+    h.msg_control = guest_ptr(g.msg_control, mem);
+    h.msg_controllen = g.msg_controllen as usize;
+    h.msg_flags = g.msg_flags;
+    Some((h, iov))
+}
+
+pub(super) fn sendmsg(mem: *mut libc::c_void, fd: u64, msg: u64, flags: u64) -> i64 {
+    let g = unsafe { *(adt(msg, mem) as *const GuestMsghdr) };
+    let Some((h, _iov)) = msghdr_from_guest(mem, &g) else {
+        return -(libc::EMSGSIZE as i64);
+    };
+    host_result(unsafe { libc::sendmsg(fd as i32, &h, flags as i32) } as i64)
+}
+
+pub(super) fn recvmsg(mem: *mut libc::c_void, fd: u64, msg: u64, flags: u64) -> i64 {
+    let gp = adt(msg, mem) as *mut GuestMsghdr;
+    let g = unsafe { *gp };
+    let Some((mut h, _iov)) = msghdr_from_guest(mem, &g) else {
+        return -(libc::EMSGSIZE as i64);
+    };
+    let r = host_result(unsafe { libc::recvmsg(fd as i32, &mut h, flags as i32) } as i64);
+    if r >= 0 {
+        unsafe {
+            (*gp).msg_namelen = h.msg_namelen;
+            (*gp).msg_controllen = h.msg_controllen as u64;
+            (*gp).msg_flags = h.msg_flags;
+        }
+    }
+    r
+}
@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Guest path translation. With a sysroot configured, absolute paths are
+// looked up under it first and fall back to the host filesystem, like
+// qemu-user's -L. Only lookups are redirected; calls that create or remove
+// names always act on the path as given.
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use super::guest_cstr;
+
+static SYSROOT: OnceLock<PathBuf> = OnceLock::new();
+
+pub(crate) fn set_sysroot(root: PathBuf) {
+    SYSROOT.set(root).expect("sysroot already set");
+}
+
+/// A guest path, possibly rewritten to point into the sysroot.
+pub(super) struct GuestPath {
+    guest: CString,
+    host: Option<CString>,
+}
+
+impl GuestPath {
+    pub(super) fn as_ptr(&self) -> *const libc::c_char {
+        self.host.as_ref().unwrap_or(&self.guest).as_ptr()
+    }
+
+    pub(super) fn to_bytes(&self) -> &[u8] {
+        self.host.as_ref().unwrap_or(&self.guest).to_bytes()
+    }
+
+    /// The path as the guest gave it
+    pub(super) fn guest(&self) -> &[u8] {
+        self.guest.to_bytes()
+    }
+}
+
+/// Resolve the guest path at `addr` for a lookup. Fails as guest_cstr()
+/// does.
+pub(super) fn lookup(addr: u64, mem: *mut libc::c_void) -> Result<GuestPath, i64> {
+    let guest = guest_cstr(addr, mem)?;
+    let host = SYSROOT.get().and_then(|root| {
+        let p = guest.to_bytes();
+        if p.first() != Some(&b'/') {
+            return None;
+        }
+        let mut joined = root.as_os_str().as_bytes().to_vec();
+        joined.extend_from_slice(p);
+        let joined = CString::new(joined).ok()?;
+        // Dangling symlinks still count as present in the sysroot
+        let r = unsafe {
+            libc::faccessat(libc::AT_FDCWD, joined.as_ptr(), libc::F_OK, libc::AT_SYMLINK_NOFOLLOW)
+        };
+        if r == 0 { Some(joined) } else { None }
+    });
+    Ok(GuestPath { guest, host })
+}
@@ -0,0 +1,250 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Syscall policy, checked before dispatch. A policy file holds one rule per
+// line:
+//
+//   default allow|deny [ERRNO]   what to do with syscalls without a rule
+//   allow NAME                   run the syscall
+//   deny NAME [ERRNO]            fail it (EPERM unless given)
+//   fake NAME [VALUE]            return VALUE (0) without running it
+//   log NAME                     print it strace-style, whatever else happens
+//   path DIR                     only allow filesystem access under DIR,
+//                                relative to the policy file's directory
+//   network off                  refuse to create sockets
+//
+// Path checks are made on the host path the call will use (under the
+// sysroot, if there is one) and resolve symlinks before comparing, dangling
+// ones included, since creating through one creates its target. The host
+// path is looked up again when the call runs, so a guest racing its own
+// symlinks can get around them; the network and syscall rules have no such
+// gap.
+//
+// The rules only hold while the guest cannot reach emulator memory, where
+// they are kept: every guest pointer goes through adt(), which sends
+// anything outside guest memory to a page that faults, and guest strings
+// are copied in with bounds checks. A bug in either, or in the JIT, is a
+// way out, so this is a policy for well-meaning programs and a second line
+// of defence for hostile ones, not a replacement for host sandboxing.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use super::*;
+use crate::adt;
+use crate::mapped;
+use crate::utils::terminal_error;
+
+#[derive(Clone, Copy)]
+enum Action {
+    Allow,
+    Deny(i64),
+    Fake(i64),
+}
+
+struct Policy {
+    default: Action,
+    rules: HashMap<u64, Action>,
+    logged: Vec<u64>,
+    paths: Vec<PathBuf>,
+    network: bool,
+}
+
+/// What to do with one syscall.
+pub(super) struct Verdict {
+    /// Some(ret) to skip the call and return ret
+    pub ret: Option<i64>,
+    pub log: bool,
+}
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+fn errno(name: &str) -> Option<i64> {
+    let e = match name {
+        "EPERM" => libc::EPERM,
+        "ENOENT" => libc::ENOENT,
+        "EIO" => libc::EIO,
+        "EBADF" => libc::EBADF,
+        "EAGAIN" => libc::EAGAIN,
+        "ENOMEM" => libc::ENOMEM,
+        "EACCES" => libc::EACCES,
+        "EEXIST" => libc::EEXIST,
+        "ENOTDIR" => libc::ENOTDIR,
+        "EINVAL" => libc::EINVAL,
+        "ENOSPC" => libc::ENOSPC,
+        "EROFS" => libc::EROFS,
+        "ENOSYS" => libc::ENOSYS,
+        "ENOTSUP" => libc::ENOTSUP,
+        "EAFNOSUPPORT" => libc::EAFNOSUPPORT,
+        "ENETUNREACH" => libc::ENETUNREACH,
+        "ECONNREFUSED" => libc::ECONNREFUSED,
+        _ => return name.parse().ok(),
+    };
+    Some(e as i64)
+}
+
+pub(crate) fn load_policy(file: &Path) {
+    let text = std::fs::read_to_string(file).unwrap_or_else(|_| terminal_error("Error reading policy file"));
+    let mut p = Policy {
+        default: Action::Allow,
+        rules: HashMap::new(),
+        logged: Vec::new(),
+        paths: Vec::new(),
+        network: true,
+    };
+    for (i, line) in text.lines().enumerate() {
+        let bad = |what: &str| -> ! { terminal_error(&format!("policy line {}: {}", i + 1, what)) };
+        let line = line.split('#').next().unwrap().trim();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some(&kw) = words.first() else {
+            continue;
+        };
+        let arg = |n: usize| words.get(n).copied();
+        let nr = || {
+            let name = arg(1).unwrap_or_else(|| bad("missing syscall name"));
+            strace::number(name).unwrap_or_else(|| bad(&format!("unknown syscall {}", name)))
+        };
+        let err = |n: usize| {
+            arg(n).map_or(libc::EPERM as i64, |e| errno(e).unwrap_or_else(|| bad(&format!("unknown errno {}", e))))
+        };
+        match kw {
+            "default" => {
+                p.default = match arg(1) {
+                    Some("allow") => Action::Allow,
+                    Some("deny") => Action::Deny(err(2)),
+                    _ => bad("expected allow or deny"),
+                }
+            }
+            "allow" => {
+                p.rules.insert(nr(), Action::Allow);
+            }
+            "deny" => {
+                p.rules.insert(nr(), Action::Deny(err(2)));
+            }
+            "fake" => {
+                let v = arg(2).map_or(0, |v| v.parse().unwrap_or_else(|_| bad("bad return value")));
+                p.rules.insert(nr(), Action::Fake(v));
+            }
+            "log" => p.logged.push(nr()),
+            "path" => {
+                // relative to the policy file, not wherever we were started
+                let dir = file.parent().unwrap_or(Path::new(".")).join(arg(1).unwrap_or_else(|| bad("missing directory")));
+                p.paths.push(std::fs::canonicalize(dir).unwrap_or_else(|_| bad("cannot resolve directory")));
+            }
+            "network" => {
+                p.network = match arg(1) {
+                    Some("on") => true,
+                    Some("off") => false,
+                    _ => bad("expected on or off"),
+                }
+            }
+            _ => bad(&format!("unknown rule {}", kw)),
+        }
+    }
+    if POLICY.set(p).is_err() {
+        panic!("policy already loaded");
+    }
+}
+
+/// Resolve a path as the host would, without following the last component
+/// unless `follow` is set. A dangling symlink resolves to its target.
+fn resolve(dirfd: i32, path: &[u8], follow: bool) -> Option<PathBuf> {
+    let p = Path::new(OsStr::from_bytes(path));
+    let mut full = if p.is_absolute() {
+        p.to_path_buf()
+    } else if dirfd == libc::AT_FDCWD {
+        std::env::current_dir().ok()?.join(p)
+    } else {
+        std::fs::read_link(format!("/proc/self/fd/{}", dirfd)).ok()?.join(p)
+    };
+    // as many links as the kernel follows before ELOOP
+    for _ in 0..40 {
+        if follow && let Ok(c) = std::fs::canonicalize(&full) {
+            return Some(c);
+        }
+        let resolved = match full.file_name() {
+            Some(name) => std::fs::canonicalize(full.parent()?).ok()?.join(name),
+            None => return std::fs::canonicalize(&full).ok(),
+        };
+        if !follow {
+            return Some(resolved);
+        }
+        match std::fs::read_link(&resolved) {
+            Ok(target) => full = resolved.parent()?.join(target),
+            Err(_) => return Some(resolved),
+        }
+    }
+    None
+}
+
+fn path_allowed(p: &Policy, dirfd: u64, path: &[u8], follow: bool) -> bool {
+    if p.paths.is_empty() {
+        return true;
+    }
+    match resolve(dirfd as i32, path, follow) {
+        Some(r) => p.paths.iter().any(|d| r.starts_with(d)),
+        None => false,
+    }
+}
+
+fn sockaddr_allowed(p: &Policy, addr: u64, len: u64, mem: *mut libc::c_void) -> bool {
+    if p.paths.is_empty() || addr == 0 || len <= 2 {
+        return true;
+    }
+    // the kernel reads no further than a sockaddr_un
+    let len = len.min(std::mem::size_of::<libc::sockaddr_un>() as u64);
+    if !mapped(addr, len) {
+        // the call fails with EFAULT
+        return true;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(adt(addr, mem) as *const u8, len as usize) };
+    if u16::from_ne_bytes([bytes[0], bytes[1]]) as i32 != libc::AF_UNIX || bytes[2] == 0 {
+        // only filesystem sockets have paths; abstract ones start with NUL
+        return true;
+    }
+    let path = bytes[2..].split(|&b| b == 0).next().unwrap_or_default();
+    path_allowed(p, libc::AT_FDCWD as u64, path, true)
+}
+
+/// Check a syscall against the policy.
+pub(super) fn check(nr: u64, a: &[u64; 6], mem: *mut libc::c_void) -> Verdict {
+    let Some(p) = POLICY.get() else {
+        return Verdict { ret: None, log: false };
+    };
+    let log = p.logged.contains(&nr);
+    let denied = Verdict { ret: Some(-(libc::EACCES as i64)), log };
+    // the path as the call will use it: lookups go through the sysroot. A
+    // path that cannot be read fails the call the same way whatever the
+    // policy says.
+    let guest = |dirfd: u64, addr: u64| {
+        guest_cstr(addr, mem).map_or(true, |g| path_allowed(p, dirfd, g.to_bytes(), false))
+    };
+    let looked_up = |dirfd: u64, addr: u64, follow: bool| {
+        path::lookup(addr, mem).map_or(true, |l| path_allowed(p, dirfd, l.to_bytes(), follow))
+    };
+    let paths_ok = match nr {
+        SYS_OPENAT | SYS_FACCESSAT | SYS_FACCESSAT2 => looked_up(a[0], a[1], true),
+        SYS_READLINKAT => looked_up(a[0], a[1], false),
+        SYS_MKDIRAT | SYS_UNLINKAT => guest(a[0], a[1]),
+        SYS_CHDIR => looked_up(libc::AT_FDCWD as u64, a[0], true),
+        SYS_RENAMEAT2 => guest(a[0], a[1]) && guest(a[2], a[3]),
+        SYS_BIND | SYS_CONNECT => sockaddr_allowed(p, a[1], a[2], mem),
+        _ => true,
+    };
+    if !paths_ok || (!p.network && nr == SYS_SOCKET) {
+        return denied;
+    }
+    let ret = match p.rules.get(&nr).copied().unwrap_or(p.default) {
+        Action::Allow => None,
+        Action::Deny(e) => Some(-e),
+        Action::Fake(v) => Some(v),
+    };
+    // exit has to happen regardless
+    if is_exit(nr) {
+        return Verdict { ret: None, log };
+    }
+    Verdict { ret, log }
+}
@@ -0,0 +1,181 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Readiness-based I/O multiplexing. RISC-V has no poll/select/epoll_wait,
+// only the variants taking a signal mask and timespec. struct pollfd,
+// fd_set, timespec and itimerspec match the host; struct epoll_event does
+// not (x86-64 packs it to 12 bytes, RISC-V pads it to 16), and pselect6
+// passes its signal mask through a struct holding a guest pointer.
+
+use super::guest_ptr;
+use super::host_result;
+use crate::adt;
+use crate::mapped;
+
+/// struct epoll_event as seen by a RISC-V guest
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GuestEpollEvent {
+    events: u32,
+    data: u64,
+}
+
+pub(super) fn ppoll(mem: *mut libc::c_void, fds: u64, nfds: u64, tsp: u64, sigmask: u64, sigsetsize: u64) -> i64 {
+    host_result(unsafe {
+        libc::syscall(
+            libc::SYS_ppoll,
+            adt(fds, mem),
+            nfds as libc::nfds_t,
+            guest_ptr(tsp, mem),
+            guest_ptr(sigmask, mem),
+            sigsetsize as usize,
+        )
+    })
+}
+
+pub(super) fn pselect6(
+    mem: *mut libc::c_void,
+    nfds: u64,
+    readfds: u64,
+    writefds: u64,
+    exceptfds: u64,
+    tsp: u64,
+    sig: u64,
+) -> i64 {
+    // { const sigset_t *ss; size_t ss_len; }
+    let mut host_sig = [0u64; 2];
+    let sigp = if sig == 0 {
+        std::ptr::null_mut()
+    } else {
+        let g = adt(sig, mem) as *const [u64; 2];
+        let g = unsafe { *g };
+        host_sig[0] = guest_ptr(g[0], mem) as u64;
+        host_sig[1] = g[1];
+        host_sig.as_mut_ptr()
+    };
+    host_result(unsafe {
+        libc::syscall(
+            libc::SYS_pselect6,
+            nfds as i32,
+            guest_ptr(readfds, mem),
+            guest_ptr(writefds, mem),
+            guest_ptr(exceptfds, mem),
+            guest_ptr(tsp, mem),
+            sigp,
+        )
+    })
+}
+
+pub(super) fn epoll_create1(flags: u64) -> i64 {
+    host_result(unsafe { libc::epoll_create1(flags as i32) } as i64)
+}
+
+pub(super) fn epoll_ctl(mem: *mut libc::c_void, epfd: u64, op: u64, fd: u64, event: u64) -> i64 {
+    let mut ev = libc::epoll_event { events: 0, u64: 0 };
+    let evp = if event == 0 {
+        std::ptr::null_mut()
+    } else {
+        let g = unsafe { *(adt(event, mem) as *const GuestEpollEvent) };
+        ev.events = g.events;
+        ev.u64 = g.data;
+        &mut ev as *mut libc::epoll_event
+    };
+    host_result(unsafe { libc::epoll_ctl(epfd as i32, op as i32, fd as i32, evp) } as i64)
+}
+
+pub(super) fn epoll_pwait(
+    mem: *mut libc::c_void,
+    epfd: u64,
+    events: u64,
+    maxevents: u64,
+    timeout: u64,
+    sigmask: u64,
+    sigsetsize: u64,
+) -> i64 {
+    epoll_wait_common(mem, events, maxevents, |evs, maxevents| unsafe {
+        libc::syscall(
+            libc::SYS_epoll_pwait,
+            epfd as i32,
+            evs,
+            maxevents,
+            timeout as i32,
+            guest_ptr(sigmask, mem),
+            sigsetsize as usize,
+        )
+    })
+}
+
+pub(super) fn epoll_pwait2(
+    mem: *mut libc::c_void,
+    epfd: u64,
+    events: u64,
+    maxevents: u64,
+    tsp: u64,
+    sigmask: u64,
+    sigsetsize: u64,
+) -> i64 {
+    epoll_wait_common(mem, events, maxevents, |evs, maxevents| unsafe {
+        libc::syscall(
+            libc::SYS_epoll_pwait2,
+            epfd as i32,
+            evs,
+            maxevents,
+            guest_ptr(tsp, mem),
+            guest_ptr(sigmask, mem),
+            sigsetsize as usize,
+        )
+    })
+}
+
+fn epoll_wait_common(
+    mem: *mut libc::c_void,
+    events: u64,
+    maxevents: u64,
+    wait: impl FnOnce(*mut libc::epoll_event, i32) -> i64,
+) -> i64 {
+    // the kernel's limits: EP_MAX_EVENTS, then the whole buffer must be
+    // writable, which also bounds what we allocate here
+    let size = std::mem::size_of::<GuestEpollEvent>() as u64;
+    if maxevents == 0 || maxevents > i32::MAX as u64 / size {
+        return -(libc::EINVAL as i64);
+    }
+    if !mapped(events, maxevents * size) {
+        return -(libc::EFAULT as i64);
+    }
+    let mut host = vec![libc::epoll_event { events: 0, u64: 0 }; maxevents as usize];
+    let r = host_result(wait(host.as_mut_ptr(), maxevents as i32));
+    if r > 0 {
+        let guest = adt(events, mem) as *mut GuestEpollEvent;
+        for (i, ev) in host.iter().take(r as usize).enumerate() {
+            unsafe {
+                *guest.add(i) = GuestEpollEvent {
+                    events: ev.events,
+                    data: ev.u64,
+                };
+            }
+        }
+    }
+    r
+}
+
+pub(super) fn eventfd2(initval: u64, flags: u64) -> i64 {
+    host_result(unsafe { libc::eventfd(initval as u32, flags as i32) } as i64)
+}
+
+pub(super) fn timerfd_create(clockid: u64, flags: u64) -> i64 {
+    host_result(unsafe { libc::timerfd_create(clockid as i32, flags as i32) } as i64)
+}
+
+pub(super) fn timerfd_settime(mem: *mut libc::c_void, fd: u64, flags: u64, new: u64, old: u64) -> i64 {
+    host_result(unsafe {
+        libc::timerfd_settime(
+            fd as i32,
+            flags as i32,
+            adt(new, mem) as *const libc::itimerspec,
+            guest_ptr(old, mem) as *mut libc::itimerspec,
+        )
+    } as i64)
+}
+
+pub(super) fn timerfd_gettime(mem: *mut libc::c_void, fd: u64, cur: u64) -> i64 {
+    host_result(unsafe { libc::timerfd_gettime(fd as i32, adt(cur, mem) as *mut libc::itimerspec) } as i64)
+}
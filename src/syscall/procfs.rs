@@ -0,0 +1,123 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Emulated /proc entries. Anything under /proc/self (or /proc/<our pid>)
+// that describes the process is synthesised from the guest's view of
+// itself rather than leaking the emulator's, and served from a memfd.
+
+use std::ffi::CString;
+use std::fmt::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use super::host_result;
+
+/// One mapping in /proc/self/maps.
+pub(crate) struct Region {
+    pub start: u64,
+    pub end: u64,
+    /// ELF segment flags (PF_R, PF_W, PF_X)
+    pub flags: u32,
+    pub offset: u64,
+    pub name: String,
+}
+
+/// The guest process as it should see itself.
+pub(crate) struct Process {
+    pub exe: PathBuf,
+    pub argv: Vec<Vec<u8>>,
+    pub regions: Vec<Region>,
+    pub auxv: Vec<(u64, u64)>,
+    pub isa: String,
+}
+
+static PROCESS: OnceLock<Process> = OnceLock::new();
+
+pub(crate) fn set_process(p: Process) {
+    if PROCESS.set(p).is_err() {
+        panic!("guest process already set");
+    }
+}
+
+/// The part of a /proc path after /proc/self/, if it names our own process.
+fn self_entry(path: &[u8]) -> Option<&[u8]> {
+    let rest = path.strip_prefix(b"/proc/")?;
+    let slash = rest.iter().position(|c| *c == b'/')?;
+    let (pid, entry) = (&rest[..slash], &rest[slash + 1..]);
+    if pid == b"self" || pid == unsafe { libc::getpid() }.to_string().as_bytes() {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+fn maps(p: &Process) -> Vec<u8> {
+    let mut s = String::new();
+    for r in p.regions.iter() {
+        let line = format!(
+            "{:08x}-{:08x} {}{}{}p {:08x} 00:00 0",
+            r.start,
+            r.end,
+            if r.flags & 4 != 0 { 'r' } else { '-' },
+            if r.flags & 2 != 0 { 'w' } else { '-' },
+            if r.flags & 1 != 0 { 'x' } else { '-' },
+            r.offset,
+        );
+        if r.name.is_empty() {
+            let _ = writeln!(s, "{}", line);
+        } else {
+            // the kernel pads the name out to a fixed column
+            let _ = writeln!(s, "{:<72} {}", line, r.name);
+        }
+    }
+    s.into_bytes()
+}
+
+fn cpuinfo(p: &Process) -> Vec<u8> {
+    format!("processor\t: 0\nhart\t\t: 0\nisa\t\t: {}\nmmu\t\t: sv39\n\n", p.isa).into_bytes()
+}
+
+fn contents(path: &[u8]) -> Option<Vec<u8>> {
+    let p = PROCESS.get()?;
+    if path == b"/proc/cpuinfo" {
+        return Some(cpuinfo(p));
+    }
+    match self_entry(path)? {
+        b"maps" => Some(maps(p)),
+        b"auxv" => Some(p.auxv.iter().flat_map(|(k, v)| [k.to_le_bytes(), v.to_le_bytes()]).flatten().collect()),
+        b"cmdline" => Some(p.argv.iter().flat_map(|a| a.iter().copied().chain([0])).collect()),
+        _ => None,
+    }
+}
+
+/// Open an emulated /proc file. Returns None for paths that go to the host.
+pub(super) fn open(path: &[u8], flags: u64, mode: u64) -> Option<i64> {
+    if self_entry(path) == Some(b"exe") {
+        let exe = CString::new(PROCESS.get()?.exe.as_os_str().as_bytes()).ok()?;
+        return Some(host_result(unsafe { libc::open(exe.as_ptr(), flags as i32, mode as libc::c_uint) } as i64));
+    }
+    let data = contents(path)?;
+    let mfd_flags = if flags & libc::O_CLOEXEC as u64 != 0 { libc::MFD_CLOEXEC } else { 0 };
+    let fd = unsafe { libc::memfd_create(c"riscv-um-proc".as_ptr(), mfd_flags) };
+    if fd < 0 {
+        return Some(host_result(fd as i64));
+    }
+    unsafe {
+        libc::write(fd, data.as_ptr() as *const libc::c_void, data.len());
+        libc::lseek(fd, 0, libc::SEEK_SET);
+    }
+    Some(fd as i64)
+}
+
+/// readlink() on /proc/self/exe gives the guest executable, not the emulator.
+pub(super) fn readlink(path: &[u8], buf: *mut libc::c_void, size: u64) -> Option<i64> {
+    if self_entry(path) != Some(b"exe") {
+        return None;
+    }
+    let exe = PROCESS.get()?.exe.as_os_str().as_bytes();
+    let n = exe.len().min(size as usize);
+    unsafe {
+        libc::memcpy(buf, exe.as_ptr() as *const libc::c_void, n);
+    }
+    Some(n as i64)
+}
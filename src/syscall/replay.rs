@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Syscall record and replay. Recording saves the initial stack (which holds
+// the environment and AT_RANDOM) and, for every syscall, its number,
+// arguments, result and the guest memory it may have written. Replaying
+// restores all of that instead of running the call, so a guest sees the
+// exact same inputs and nothing touches the host apart from exit.
+//
+// Output regions are over-approximated where the exact size is awkward to
+// know (e.g. sockaddr buffers). This is harmless: up to that call replay is
+// identical to the recording, so bytes the kernel did not write hold the
+// same values in both.
+//
+// File format, all little endian: the magic, then sp, the stack length and
+// the stack bytes, then one record per syscall: nr, a0-a5, ret, the region
+// count, and for each region its address, length and contents.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+
+use super::*;
+use crate::adt;
+use crate::utils::ConvertibleError;
+use crate::utils::terminal_error;
+
+const MAGIC: &[u8; 8] = b"RVUMTRC1";
+
+enum Mode {
+    Record(BufWriter<File>),
+    Replay(BufReader<File>, u64),
+}
+
+static MODE: OnceLock<Mutex<Mode>> = OnceLock::new();
+
+pub(crate) fn start_recording(path: &Path) {
+    let mut w = BufWriter::new(File::create(path).e("Unable to create trace file"));
+    w.write_all(MAGIC).e("Error writing trace file");
+    let _ = MODE.set(Mutex::new(Mode::Record(w)));
+}
+
+pub(crate) fn start_replay(path: &Path) {
+    let mut r = BufReader::new(File::open(path).e("Unable to open trace file"));
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic).e("Error reading trace file");
+    if &magic != MAGIC {
+        terminal_error("Not a riscv-um trace file");
+    }
+    let _ = MODE.set(Mutex::new(Mode::Replay(r, 0)));
+}
+
+#[inline(always)]
+pub(super) fn active() -> bool {
+    MODE.get().is_some()
+}
+
+fn put(w: &mut BufWriter<File>, v: u64) {
+    w.write_all(&v.to_le_bytes()).e("Error writing trace file");
+}
+
+fn get(r: &mut BufReader<File>) -> u64 {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b).e("Trace file ended early");
+    u64::from_le_bytes(b)
+}
+
+fn put_region(w: &mut BufWriter<File>, mem: *mut libc::c_void, addr: u64, len: u64) {
+    put(w, addr);
+    put(w, len);
+    let bytes = unsafe { std::slice::from_raw_parts(adt(addr, mem) as *const u8, len as usize) };
+    w.write_all(bytes).e("Error writing trace file");
+}
+
+fn get_region(r: &mut BufReader<File>, mem: *mut libc::c_void) -> u64 {
+    let addr = get(r);
+    let len = get(r);
+    let bytes = unsafe { std::slice::from_raw_parts_mut(adt(addr, mem) as *mut u8, len as usize) };
+    r.read_exact(bytes).e("Trace file ended early");
+    addr
+}
+
+/// Record the initial stack, or restore it when replaying.
+pub(crate) fn initial_stack(mem: *mut libc::c_void, sp: &mut u64, top: u64) {
+    let Some(mode) = MODE.get() else {
+        return;
+    };
+    match *mode.lock().unwrap() {
+        Mode::Record(ref mut w) => put_region(w, mem, *sp, top - *sp),
+        Mode::Replay(ref mut r, _) => *sp = get_region(r, mem),
+    }
+}
+
+/// Guest memory a syscall may have written, given its result.
+fn outputs(nr: u64, a: &[u64; 6], ret: i64, mem: *mut libc::c_void) -> Vec<(u64, u64)> {
+    let mut out: Vec<(u64, u64)> = Vec::new();
+    if ret < 0 {
+        return out;
+    }
+    let u32_at = |addr: u64| unsafe { *(adt(addr, mem) as *const u32) } as u64;
+    let mut opt = |addr: u64, len: u64| {
+        if addr != 0 && len != 0 {
+            out.push((addr, len));
+        }
+    };
+    match nr {
+        SYS_READ | SYS_GETDENTS64 => opt(a[1], ret as u64),
+        SYS_GETCWD => opt(a[0], ret as u64),
+        SYS_READLINKAT => opt(a[2], ret as u64),
+        SYS_IOCTL => match a[1] {
+            // TCGETS, TIOCGWINSZ, TIOCGPGRP, FIONREAD
+            0x5401 => opt(a[2], 36),
+            0x5413 => opt(a[2], 8),
+            0x540f | 0x541b => opt(a[2], 4),
+            _ => {}
+        },
+        SYS_SOCKETPAIR => opt(a[3], 8),
+        SYS_ACCEPT | SYS_ACCEPT4 | SYS_GETSOCKNAME | SYS_GETPEERNAME => {
+            if a[2] != 0 {
+                opt(a[1], u32_at(a[2]).min(128));
+                opt(a[2], 4);
+            }
+        }
+        SYS_RECVFROM => {
+            opt(a[1], ret as u64);
+            if a[5] != 0 {
+                opt(a[4], u32_at(a[5]).min(128));
+                opt(a[5], 4);
+            }
+        }
+        SYS_GETSOCKOPT => {
+            if a[4] != 0 {
+                opt(a[3], u32_at(a[4]).min(256));
+                opt(a[4], 4);
+            }
+        }
+        SYS_RECVMSG => {
+            let h = adt(a[1], mem) as *const u64;
+            let (name, namelen, iov, iovlen, control, controllen) = unsafe {
+                (*h, *h.add(1) & 0xffff_ffff, *h.add(2), *h.add(3), *h.add(4), *h.add(5))
+            };
+            opt(a[1], 56);
+            opt(name, namelen);
+            opt(control, controllen);
+            let mut left = ret as u64;
+            let iov = adt(iov, mem) as *const [u64; 2];
+            for i in 0..iovlen.min(1024) as usize {
+                let [base, len] = unsafe { *iov.add(i) };
+                opt(base, len.min(left));
+                left -= len.min(left);
+            }
+        }
+        SYS_EPOLL_PWAIT | SYS_EPOLL_PWAIT2 => opt(a[1], ret as u64 * 16),
+        SYS_PPOLL => {
+            opt(a[0], a[1] * 8);
+            opt(a[2], 16);
+        }
+        SYS_PSELECT6 => {
+            let setlen = a[0].div_ceil(64) * 8;
+            opt(a[1], setlen);
+            opt(a[2], setlen);
+            opt(a[3], setlen);
+            opt(a[4], 16);
+        }
+        SYS_TIMERFD_SETTIME => opt(a[3], 32),
+        SYS_TIMERFD_GETTIME => opt(a[1], 32),
+        _ => {}
+    }
+    out
+}
+
+/// Save a completed syscall.
+pub(super) fn record(nr: u64, a: &[u64; 6], ret: i64, mem: *mut libc::c_void) {
+    let mut mode = MODE.get().unwrap().lock().unwrap();
+    let Mode::Record(ref mut w) = *mode else {
+        return;
+    };
+    put(w, nr);
+    for v in a.iter() {
+        put(w, *v);
+    }
+    put(w, ret as u64);
+    let regions = outputs(nr, a, ret, mem);
+    put(w, regions.len() as u64);
+    for (addr, len) in regions {
+        put_region(w, mem, addr, len);
+    }
+}
+
+/// Write out what is buffered of the recording, as the guest exits.
+pub(crate) fn finish_recording() {
+    // still held if the guest faulted while a syscall was being saved
+    let Some(Ok(mut mode)) = MODE.get().map(|m| m.try_lock()) else {
+        return;
+    };
+    if let Mode::Record(ref mut w) = *mode {
+        w.flush().e("Error writing trace file");
+    }
+}
+
+/// Replay the next syscall, returning its recorded result. exit is left to
+/// run for real.
+pub(super) fn replay(nr: u64, a: &[u64; 6], mem: *mut libc::c_void) -> Option<i64> {
+    let mut mode = MODE.get().unwrap().lock().unwrap();
+    let Mode::Replay(ref mut r, ref mut n) = *mode else {
+        return None;
+    };
+    *n += 1;
+    let rnr = get(r);
+    let mut ra = [0u64; 6];
+    for v in ra.iter_mut() {
+        *v = get(r);
+    }
+    if rnr != nr || ra != *a {
+        terminal_error(&format!(
+            "Replay diverged at syscall {}: expected {} {:x?}, guest made {} {:x?}",
+            n, rnr, ra, nr, a
+        ));
+    }
+    let ret = get(r) as i64;
+    for _ in 0..get(r) {
+        get_region(r, mem);
+    }
+    if is_exit(nr) { None } else { Some(ret) }
+}
@@ -0,0 +1,447 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// qemu-user style -strace output. The call and its decoded arguments are
+// printed before it runs (so blocking calls show up), the result after.
+// Output buffers are shown as pointers since they are not filled in yet.
+
+use std::ffi::CStr;
+use std::fmt::Write;
+use std::io::Write as IoWrite;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use super::*;
+use crate::adt;
+use crate::mapped;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_strace(on: bool) {
+    ENABLED.store(on, Ordering::Relaxed);
+}
+
+#[inline(always)]
+pub(super) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// How to decode one syscall argument.
+#[derive(Clone, Copy)]
+enum Arg {
+    Dec,
+    Hex,
+    Oct,
+    Fd,
+    /// directory fd for the *at() calls
+    AtFd,
+    Str,
+    /// output pointer, not decoded
+    Ptr,
+    /// input buffer whose length is the given argument
+    Buf(usize),
+    OpenFlags,
+    AtFlags,
+    UnlinkFlags,
+    Ioctl,
+    SockDomain,
+    SockType,
+    /// sockaddr whose length is the given argument
+    Sockaddr(usize),
+    /// pollfd array whose count is the given argument
+    Pollfds(usize),
+    EpollOp,
+    EpollEvent,
+    Timespec,
+    Itimerspec,
+    Msghdr,
+}
+
+use Arg::*;
+
+/// Name and argument layout of a syscall, by RISC-V number.
+fn describe(nr: u64) -> Option<(&'static str, &'static [Arg])> {
+    Some(match nr {
+        SYS_GETCWD => ("getcwd", &[Ptr, Dec]),
+        SYS_EVENTFD2 => ("eventfd2", &[Dec, Hex]),
+        SYS_EPOLL_CREATE1 => ("epoll_create1", &[Hex]),
+        SYS_EPOLL_CTL => ("epoll_ctl", &[Fd, EpollOp, Fd, EpollEvent]),
+        SYS_EPOLL_PWAIT => ("epoll_pwait", &[Fd, Ptr, Dec, Dec, Hex, Dec]),
+        SYS_IOCTL => ("ioctl", &[Fd, Ioctl, Hex]),
+        SYS_MKDIRAT => ("mkdirat", &[AtFd, Str, Oct]),
+        SYS_UNLINKAT => ("unlinkat", &[AtFd, Str, UnlinkFlags]),
+        SYS_FACCESSAT => ("faccessat", &[AtFd, Str, Oct]),
+        SYS_CHDIR => ("chdir", &[Str]),
+        SYS_FCHDIR => ("fchdir", &[Fd]),
+        SYS_OPENAT => ("openat", &[AtFd, Str, OpenFlags, Oct]),
+        SYS_CLOSE => ("close", &[Fd]),
+        SYS_GETDENTS64 => ("getdents64", &[Fd, Ptr, Dec]),
+        SYS_READ => ("read", &[Fd, Ptr, Dec]),
+        SYS_WRITE => ("write", &[Fd, Buf(2), Dec]),
+        SYS_PSELECT6 => ("pselect6", &[Dec, Hex, Hex, Hex, Timespec, Hex]),
+        SYS_PPOLL => ("ppoll", &[Pollfds(1), Dec, Timespec, Hex, Dec]),
+        SYS_READLINKAT => ("readlinkat", &[AtFd, Str, Ptr, Dec]),
+        SYS_TIMERFD_CREATE => ("timerfd_create", &[Dec, Hex]),
+        SYS_TIMERFD_SETTIME => ("timerfd_settime", &[Fd, Hex, Itimerspec, Ptr]),
+        SYS_TIMERFD_GETTIME => ("timerfd_gettime", &[Fd, Ptr]),
+        SYS_EXIT => ("exit", &[Dec]),
+        SYS_EXIT_GROUP => ("exit_group", &[Dec]),
+        SYS_SOCKET => ("socket", &[SockDomain, SockType, Dec]),
+        SYS_SOCKETPAIR => ("socketpair", &[SockDomain, SockType, Dec, Ptr]),
+        SYS_BIND => ("bind", &[Fd, Sockaddr(2), Dec]),
+        SYS_LISTEN => ("listen", &[Fd, Dec]),
+        SYS_ACCEPT => ("accept", &[Fd, Ptr, Ptr]),
+        SYS_CONNECT => ("connect", &[Fd, Sockaddr(2), Dec]),
+        SYS_GETSOCKNAME => ("getsockname", &[Fd, Ptr, Ptr]),
+        SYS_GETPEERNAME => ("getpeername", &[Fd, Ptr, Ptr]),
+        SYS_SENDTO => ("sendto", &[Fd, Buf(2), Dec, Hex, Sockaddr(5), Dec]),
+        SYS_RECVFROM => ("recvfrom", &[Fd, Ptr, Dec, Hex, Ptr, Ptr]),
+        SYS_SETSOCKOPT => ("setsockopt", &[Fd, Dec, Dec, Buf(4), Dec]),
+        SYS_GETSOCKOPT => ("getsockopt", &[Fd, Dec, Dec, Ptr, Ptr]),
+        SYS_SHUTDOWN => ("shutdown", &[Fd, Dec]),
+        SYS_SENDMSG => ("sendmsg", &[Fd, Msghdr, Hex]),
+        SYS_RECVMSG => ("recvmsg", &[Fd, Ptr, Hex]),
+        SYS_ACCEPT4 => ("accept4", &[Fd, Ptr, Ptr, Hex]),
+        SYS_RENAMEAT2 => ("renameat2", &[AtFd, Str, AtFd, Str, Hex]),
+        SYS_FACCESSAT2 => ("faccessat2", &[AtFd, Str, Oct, AtFlags]),
+        SYS_EPOLL_PWAIT2 => ("epoll_pwait2", &[Fd, Ptr, Dec, Timespec, Hex, Dec]),
+        _ => return None,
+    })
+}
+
+/// Name of the syscall with the given number.
+pub(crate) fn name(nr: u64) -> Option<&'static str> {
+    describe(nr).map(|(name, _)| name)
+}
+
+/// Number of the syscall with the given name.
+pub(super) fn number(name: &str) -> Option<u64> {
+    (0..512).find(|nr| describe(*nr).is_some_and(|(n, _)| n == name))
+}
+
+fn flags(out: &mut String, v: u64, names: &[(u64, &str)]) {
+    let mut rest = v;
+    let mut first = true;
+    for (bit, name) in names {
+        if *bit != 0 && v & bit == *bit {
+            let _ = write!(out, "{}{}", if first { "" } else { "|" }, name);
+            rest &= !bit;
+            first = false;
+        }
+    }
+    if rest != 0 || first {
+        let _ = write!(out, "{}{:#x}", if first { "" } else { "|" }, rest);
+    }
+}
+
+fn escaped(out: &mut String, bytes: &[u8], limit: usize) {
+    out.push('"');
+    for b in bytes.iter().take(limit) {
+        match *b {
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            b'\r' => out.push_str("\\r"),
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            0x20..=0x7e => out.push(*b as char),
+            _ => {
+                let _ = write!(out, "\\{:o}", b);
+            }
+        }
+    }
+    out.push('"');
+    if bytes.len() > limit {
+        out.push_str("...");
+    }
+}
+
+fn open_flags(out: &mut String, v: u64) {
+    out.push_str(match v & 3 {
+        0 => "O_RDONLY",
+        1 => "O_WRONLY",
+        2 => "O_RDWR",
+        _ => "O_ACCMODE",
+    });
+    let names: [(u64, &str); 11] = [
+        (libc::O_CREAT as u64, "O_CREAT"),
+        (libc::O_EXCL as u64, "O_EXCL"),
+        (libc::O_NOCTTY as u64, "O_NOCTTY"),
+        (libc::O_TRUNC as u64, "O_TRUNC"),
+        (libc::O_APPEND as u64, "O_APPEND"),
+        (libc::O_NONBLOCK as u64, "O_NONBLOCK"),
+        (libc::O_DIRECTORY as u64, "O_DIRECTORY"),
+        (libc::O_NOFOLLOW as u64, "O_NOFOLLOW"),
+        (libc::O_CLOEXEC as u64, "O_CLOEXEC"),
+        (libc::O_LARGEFILE as u64, "O_LARGEFILE"),
+        (libc::O_PATH as u64, "O_PATH"),
+    ];
+    if v & !3 != 0 {
+        out.push('|');
+        flags(out, v & !3, &names);
+    }
+}
+
+fn sockaddr(out: &mut String, p: *const u8, len: u64) {
+    if len < 2 {
+        out.push_str("{}");
+        return;
+    }
+    let family = unsafe { *(p as *const u16) } as i32;
+    let body = unsafe { std::slice::from_raw_parts(p, len as usize) };
+    match family {
+        libc::AF_UNIX => {
+            let path = &body[2..];
+            let end = path.iter().position(|c| *c == 0).unwrap_or(path.len());
+            out.push_str("{sun_family=AF_UNIX,sun_path=");
+            escaped(out, &path[..end], 108);
+            out.push('}');
+        }
+        libc::AF_INET if len >= 8 => {
+            let _ = write!(
+                out,
+                "{{sin_family=AF_INET,sin_port=htons({}),sin_addr=inet_addr(\"{}.{}.{}.{}\")}}",
+                u16::from_be_bytes([body[2], body[3]]),
+                body[4],
+                body[5],
+                body[6],
+                body[7]
+            );
+        }
+        libc::AF_INET6 if len >= 24 => {
+            let addr: [u8; 16] = body[8..24].try_into().unwrap();
+            let _ = write!(
+                out,
+                "{{sin6_family=AF_INET6,sin6_port=htons({}),sin6_addr={}}}",
+                u16::from_be_bytes([body[2], body[3]]),
+                std::net::Ipv6Addr::from(addr)
+            );
+        }
+        _ => {
+            let _ = write!(out, "{{sa_family={}}}", family);
+        }
+    }
+}
+
+fn timespec(out: &mut String, p: *const i64) {
+    let (s, ns) = unsafe { (*p, *p.add(1)) };
+    let _ = write!(out, "{{tv_sec={},tv_nsec={}}}", s, ns);
+}
+
+fn arg(out: &mut String, kind: Arg, a: &[u64; 6], i: usize, mem: *mut libc::c_void) {
+    let v = a[i];
+    let nullable = matches!(
+        kind,
+        Str | Buf(_) | Sockaddr(_) | Pollfds(_) | EpollEvent | Timespec | Itimerspec | Msghdr
+    );
+    if nullable && v == 0 {
+        out.push_str("NULL");
+        return;
+    }
+    // the guest memory shown, the lengths being the guest's word; printed
+    // as the bare pointer unless it is all there, as strace does
+    let shown = match kind {
+        Buf(len) => Some(a[len].min(32)),
+        Sockaddr(len) => Some(a[len].min(std::mem::size_of::<libc::sockaddr_storage>() as u64)),
+        Pollfds(n) => Some(a[n].min(16) * std::mem::size_of::<libc::pollfd>() as u64),
+        EpollEvent | Timespec => Some(16),
+        Itimerspec => Some(32),
+        Msghdr => Some(48),
+        _ => None,
+    };
+    if shown.is_some_and(|n| !mapped(v, n)) {
+        let _ = write!(out, "{:#x}", v);
+        return;
+    }
+    let shown = shown.unwrap_or_default();
+    match kind {
+        Dec => {
+            let _ = write!(out, "{}", v as i64);
+        }
+        Hex | Ptr => {
+            let _ = write!(out, "{:#x}", v);
+        }
+        Oct => {
+            let _ = if v == 0 { write!(out, "0") } else { write!(out, "0{:o}", v) };
+        }
+        Fd => {
+            let _ = write!(out, "{}", v as i32);
+        }
+        AtFd => {
+            if v as i32 == libc::AT_FDCWD {
+                out.push_str("AT_FDCWD");
+            } else {
+                let _ = write!(out, "{}", v as i32);
+            }
+        }
+        Str => match guest_cstr(v, mem) {
+            Ok(s) => escaped(out, s.to_bytes(), 256),
+            Err(_) => {
+                let _ = write!(out, "{:#x}", v);
+            }
+        },
+        Buf(len) => {
+            let s = unsafe { std::slice::from_raw_parts(adt(v, mem) as *const u8, shown as usize) };
+            escaped(out, s, 32);
+            if a[len] > shown {
+                out.push_str("...");
+            }
+        }
+        OpenFlags => open_flags(out, v),
+        AtFlags => flags(
+            out,
+            v,
+            &[
+                (libc::AT_SYMLINK_NOFOLLOW as u64, "AT_SYMLINK_NOFOLLOW"),
+                (libc::AT_EACCESS as u64, "AT_EACCESS"),
+                (libc::AT_EMPTY_PATH as u64, "AT_EMPTY_PATH"),
+            ],
+        ),
+        UnlinkFlags => flags(out, v, &[(libc::AT_REMOVEDIR as u64, "AT_REMOVEDIR")]),
+        Ioctl => {
+            out.push_str(match v {
+                0x5401 => "TCGETS",
+                0x5402 => "TCSETS",
+                0x5403 => "TCSETSW",
+                0x5404 => "TCSETSF",
+                0x540f => "TIOCGPGRP",
+                0x5410 => "TIOCSPGRP",
+                0x5413 => "TIOCGWINSZ",
+                0x5414 => "TIOCSWINSZ",
+                0x541b => "FIONREAD",
+                0x5421 => "FIONBIO",
+                0x5450 => "FIONCLEX",
+                0x5451 => "FIOCLEX",
+                _ => {
+                    let _ = write!(out, "{:#x}", v);
+                    return;
+                }
+            });
+        }
+        SockDomain => {
+            out.push_str(match v as i32 {
+                libc::AF_UNIX => "AF_UNIX",
+                libc::AF_INET => "AF_INET",
+                libc::AF_INET6 => "AF_INET6",
+                libc::AF_NETLINK => "AF_NETLINK",
+                _ => {
+                    let _ = write!(out, "{}", v);
+                    return;
+                }
+            });
+        }
+        SockType => {
+            let base = v & 0xf;
+            out.push_str(match base as i32 {
+                libc::SOCK_STREAM => "SOCK_STREAM",
+                libc::SOCK_DGRAM => "SOCK_DGRAM",
+                libc::SOCK_RAW => "SOCK_RAW",
+                libc::SOCK_SEQPACKET => "SOCK_SEQPACKET",
+                _ => "SOCK_?",
+            });
+            if v & !0xf != 0 {
+                out.push('|');
+                flags(
+                    out,
+                    v & !0xf,
+                    &[
+                        (libc::SOCK_NONBLOCK as u64, "SOCK_NONBLOCK"),
+                        (libc::SOCK_CLOEXEC as u64, "SOCK_CLOEXEC"),
+                    ],
+                );
+            }
+        }
+        Sockaddr(_) => sockaddr(out, adt(v, mem) as *const u8, shown),
+        Pollfds(_) => {
+            let p = adt(v, mem) as *const libc::pollfd;
+            out.push('[');
+            for k in 0..shown as usize / std::mem::size_of::<libc::pollfd>() {
+                let pfd = unsafe { *p.add(k) };
+                let _ = write!(out, "{}{{fd={},events=", if k == 0 { "" } else { "," }, pfd.fd);
+                flags(
+                    out,
+                    pfd.events as u64,
+                    &[
+                        (libc::POLLIN as u64, "POLLIN"),
+                        (libc::POLLPRI as u64, "POLLPRI"),
+                        (libc::POLLOUT as u64, "POLLOUT"),
+                    ],
+                );
+                out.push('}');
+            }
+            out.push(']');
+        }
+        EpollOp => {
+            out.push_str(match v as i32 {
+                libc::EPOLL_CTL_ADD => "EPOLL_CTL_ADD",
+                libc::EPOLL_CTL_DEL => "EPOLL_CTL_DEL",
+                libc::EPOLL_CTL_MOD => "EPOLL_CTL_MOD",
+                _ => "EPOLL_CTL_?",
+            });
+        }
+        EpollEvent => {
+            let p = adt(v, mem) as *const u64;
+            let (events, data) = unsafe { (*p as u32, *p.add(1)) };
+            let _ = write!(out, "{{events={:#x},data={:#x}}}", events, data);
+        }
+        Timespec => timespec(out, adt(v, mem) as *const i64),
+        Itimerspec => {
+            let p = adt(v, mem) as *const i64;
+            out.push_str("{it_interval=");
+            timespec(out, p);
+            out.push_str(",it_value=");
+            timespec(out, unsafe { p.add(2) });
+            out.push('}');
+        }
+        Msghdr => {
+            let p = adt(v, mem) as *const u64;
+            let (name, iovlen, controllen) = unsafe { (*p, *p.add(3), *p.add(5)) };
+            let _ = write!(
+                out,
+                "{{msg_name={:#x},msg_iovlen={},msg_controllen={}}}",
+                name, iovlen, controllen
+            );
+        }
+    }
+}
+
+/// Print the call about to be made.
+pub(super) fn enter(nr: u64, a: &[u64; 6], mem: *mut libc::c_void) {
+    let mut out = format!("{} ", unsafe { libc::getpid() });
+    match describe(nr) {
+        Some((name, kinds)) => {
+            out.push_str(name);
+            out.push('(');
+            for (i, kind) in kinds.iter().enumerate() {
+                if i != 0 {
+                    out.push(',');
+                }
+                arg(&mut out, *kind, a, i, mem);
+            }
+            out.push(')');
+        }
+        None => {
+            let _ = write!(
+                out,
+                "syscall_{}({:#x},{:#x},{:#x},{:#x},{:#x},{:#x})",
+                nr, a[0], a[1], a[2], a[3], a[4], a[5]
+            );
+        }
+    }
+    if is_exit(nr) {
+        out.push('\n');
+    }
+    let mut err = std::io::stderr().lock();
+    let _ = err.write_all(out.as_bytes());
+    let _ = err.flush();
+}
+
+/// Print the result of the call.
+pub(super) fn exit(ret: i64) {
+    if (-4095..0).contains(&ret) {
+        let msg = unsafe { CStr::from_ptr(libc::strerror(-ret as i32)) };
+        eprintln!(" = -1 errno={} ({})", -ret, msg.to_string_lossy());
+    } else if (0..0x10000).contains(&ret) {
+        eprintln!(" = {}", ret);
+    } else {
+        eprintln!(" = {:#x}", ret);
+    }
+}
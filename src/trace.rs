@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Instruction trace (--trace). Runs the guest on the plain interpreter and
+// logs each retired instruction to stderr: where it is, its encoding, its
+// disassembly, and what it did to registers and memory. A filter of address
+// ranges and symbols keeps the log to the code being looked at. The same
+// information can be written as a Spike commit log (--log-commits), to diff
+// a run against Spike's instruction by instruction.
+
+use std::io::Write;
+use std::sync::Mutex;
+
+use crate::adt;
+use crate::block::Stop;
+use crate::decode::Instruction;
+use crate::disasm::disasm;
+use crate::dwarf;
+use crate::interp;
+use crate::interp::REG_NAMES;
+use crate::mapped;
+use crate::symbols::Symbols;
+use crate::utils::terminal_error;
+
+/// Address ranges [start, end) to trace; empty traces everything
+pub(crate) struct Filter(Vec<(u64, u64)>);
+
+fn address(s: &str, symbols: &Symbols) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok().or_else(|| symbols.lookup(s)),
+    }
+}
+
+impl Filter {
+    /// Comma-separated START-END ranges and symbol names
+    pub(crate) fn parse(s: &str, symbols: &Symbols) -> Self {
+        let ranges = s
+            .split(',')
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                let range = match item.split_once('-') {
+                    Some((start, end)) => address(start, symbols).zip(address(end, symbols)),
+                    None => symbols.extent(item),
+                };
+                range.unwrap_or_else(|| terminal_error(&format!("Invalid trace filter \"{}\"", item)))
+            })
+            .collect();
+        Filter(ranges)
+    }
+
+    fn contains(&self, pc: u64) -> bool {
+        self.0.is_empty() || self.0.iter().any(|&(start, end)| (start..end).contains(&pc))
+    }
+}
+
+fn read(mem: *mut libc::c_void, addr: u64, len: u64) -> u64 {
+    let p = adt(addr, mem);
+    unsafe {
+        match len {
+            1 => *(p as *const u8) as u64,
+            4 => *(p as *const u32) as u64,
+            _ => *(p as *const u64),
+        }
+    }
+}
+
+/// Trace output not yet written out. Kept here rather than in a BufWriter
+/// so a crash report can flush it: the last lines before a crash are the
+/// ones that matter.
+static PENDING: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+fn emit(line: std::fmt::Arguments) {
+    let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+    let _ = writeln!(pending, "{}", line);
+    if pending.len() >= 1 << 16 {
+        let _ = std::io::stderr().write_all(&pending);
+        pending.clear();
+    }
+}
+
+/// Write out whatever the trace has buffered.
+pub(crate) fn flush() {
+    // not while the trace itself is writing (a fault mid-line)
+    if let Ok(mut pending) = PENDING.try_lock() {
+        let _ = std::io::stderr().write_all(&pending);
+        pending.clear();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+    /// Disassembly and effects, for reading
+    Plain,
+    /// Spike's --log-commits lines, for diffing against it. The guest runs
+    /// in user mode, so the privilege level is always 0.
+    Spike,
+}
+
+struct Tracer<'a> {
+    filter: &'a Filter,
+    format: Format,
+    symbols: &'a Symbols,
+    last_source: Option<String>,
+    /// Encoding of the instruction running, if it is traced
+    traced: Option<u32>,
+    /// The memory it accesses
+    access: Option<(u64, u64, bool)>,
+}
+
+impl interp::Hook for Tracer<'_> {
+    fn before(&mut self, inst: Instruction, at: u64, mem: *mut libc::c_void, registers: &[u64; 32]) {
+        self.traced = None;
+        if !self.filter.contains(at) {
+            return;
+        }
+        let word = unsafe { *(adt(at, mem) as *const u32) };
+        self.traced = Some(word);
+        self.access = interp::access(inst, registers).filter(|&(addr, len, _)| mapped(addr, len));
+        if self.format == Format::Plain {
+            // the source line, when it changes
+            let source = dwarf::line(at);
+            if source.is_some() && source != self.last_source {
+                emit(format_args!("; {}", source.as_deref().unwrap_or_default()));
+                self.last_source = source;
+            }
+        }
+        if matches!(inst, Instruction::Ecall) {
+            // logged first: the guest may write to stderr, or exit. Spike
+            // does not log the a0 write either, it happens in the kernel.
+            match self.format {
+                Format::Plain => emit(format_args!("{:<32} {:08x}  ecall", self.symbols.format(at), word)),
+                Format::Spike => emit(format_args!("core   0: 0 0x{:016x} (0x{:08x})", at, word)),
+            };
+            flush();
+        }
+    }
+
+    fn after(&mut self, inst: Instruction, at: u64, mem: *mut libc::c_void, registers: &[u64; 32], _: u64) {
+        let Some(word) = self.traced else { return };
+        if matches!(inst, Instruction::Ecall) {
+            if self.format == Format::Plain {
+                emit(format_args!("{:<75} a0={:#x}", "", registers[10]));
+            }
+            return;
+        }
+        let rd = inst.rd().map(|rd| (rd, registers[rd as usize]));
+        match self.format {
+            Format::Plain => {
+                let mut effects = String::new();
+                match self.access {
+                    Some((addr, len, true)) => effects += &format!("[{:#x}] <- {:#x}", addr, read(mem, addr, len)),
+                    Some((addr, _, false)) => effects += &format!("[{:#x}] -> ", addr),
+                    None => {}
+                }
+                if let Some((rd, v)) = rd {
+                    effects += &format!("{}={:#x}", REG_NAMES[rd as usize], v);
+                }
+                let line =
+                    format!("{:<32} {:08x}  {:<32} {}", self.symbols.format(at), word, disasm(word, at), effects);
+                emit(format_args!("{}", line.trim_end()))
+            }
+            Format::Spike => {
+                let mut line = format!("core   0: 0 0x{:016x} (0x{:08x})", at, word);
+                if let Some((rd, v)) = rd {
+                    line += &format!(" x{:<2} 0x{:016x}", rd, v);
+                }
+                match self.access {
+                    Some((addr, len, true)) => {
+                        line += &format!(" mem 0x{:016x} 0x{:0w$x}", addr, read(mem, addr, len), w = len as usize * 2)
+                    }
+                    Some((addr, _, false)) => line += &format!(" mem 0x{:016x}", addr),
+                    None => {}
+                }
+                emit(format_args!("{}", line))
+            }
+        };
+    }
+}
+
+/// Run the guest, tracing the instructions the filter selects, until a
+/// limit stops it. Returns why and how many instructions were retired.
+pub(crate) fn run(
+    filter: &Filter,
+    format: Format,
+    symbols: &Symbols,
+    mem: *mut libc::c_void,
+    registers: &mut [u64; 32],
+    pc: &mut u64,
+    max_insns: u64,
+) -> (Stop, u64) {
+    let mut tracer = Tracer { filter, format, symbols, last_source: None, traced: None, access: None };
+    let stopped = interp::run(mem, registers, pc, max_insns, &mut tracer);
+    flush();
+    stopped
+}
@@ -0,0 +1,41 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// `riscv-um disasm` on the SYSTEM encodings in tests/guest/csr.s: the Zicsr
+// instructions by name, the reserved funct3 4 and the funct3 0 words that
+// are not ecall or ebreak as unknown encodings.
+
+use std::process::Command;
+
+const GUEST: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/guest/csr");
+
+/// The disassembly of each instruction in the guest, without its address
+/// and encoding
+fn disassembly() -> Vec<String> {
+    let out = Command::new(env!("CARGO_BIN_EXE_riscv-um"))
+        .arg("disasm")
+        .arg(GUEST)
+        .output()
+        .expect("cannot run riscv-um");
+    assert!(out.status.success());
+    String::from_utf8(out.stdout)
+        .unwrap()
+        .lines()
+        .filter_map(|l| l.splitn(3, '\t').nth(2))
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn csr_instructions() {
+    let text = disassembly();
+    assert_eq!(text[..3], ["csrrw t0, fflags, ra", "csrrs t0, fflags, ra", "csrrc t0, fflags, ra"]);
+    assert_eq!(text[4..7], ["csrrwi t0, fflags, 1", "csrrsi t0, fflags, 1", "csrrci t0, fflags, 1"]);
+    assert_eq!(text[8..10], ["csrr t0, fflags", "csrw fflags, ra"]);
+}
+
+#[test]
+fn reserved_and_other_system_encodings_are_unknown() {
+    let text = disassembly();
+    assert_eq!(text[3], ".word 0x0010c2f3");
+    assert_eq!(text[7], ".word 0x10500073");
+}
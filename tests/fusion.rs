@@ -0,0 +1,37 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Differential test of instruction fusion and the JIT backends: the guest in
+// tests/guest/fusion.s must leave the same registers behind however it is
+// run. The reference is --stats, whose per-instruction loop neither fuses
+// nor compiles anything.
+
+use std::process::Command;
+
+const GUEST: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/guest/fusion");
+
+/// The registers the guest writes to stdout when run with these options
+fn registers(options: &[&str]) -> Vec<u8> {
+    let out = Command::new(env!("CARGO_BIN_EXE_riscv-um"))
+        .args(options)
+        .arg(GUEST)
+        .output()
+        .expect("cannot run riscv-um");
+    assert!(out.status.success(), "riscv-um {:?} failed: {}", options, String::from_utf8_lossy(&out.stderr));
+    assert_eq!(out.stdout.len(), 256, "riscv-um {:?} wrote no registers", options);
+    out.stdout
+}
+
+#[test]
+fn fused_and_compiled_match_the_interpreter() {
+    let reference = registers(&["--stats"]);
+    let mut modes = vec![vec![], vec!["--jit"]];
+    if cfg!(feature = "cranelift") {
+        modes.push(vec!["--jit=cranelift"]);
+    }
+    for mode in modes {
+        let got = registers(&mode);
+        for (n, (want, got)) in reference.chunks(8).zip(got.chunks(8)).enumerate() {
+            assert_eq!(got, want, "x{} differs with {:?}", n, mode);
+        }
+    }
+}
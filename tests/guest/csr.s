@@ -0,0 +1,23 @@
+# SYSTEM encodings around the Zicsr instructions, for the disassembler:
+# funct3 0 and 4 are not CSR accesses.
+#
+# Rebuild with:
+#   llvm-mc -triple=riscv64 -filetype=obj csr.s -o csr.o
+#   ld.lld -o csr csr.o
+
+.text
+.globl _start
+_start:
+  csrrw t0, fflags, ra
+  csrrs t0, fflags, ra
+  csrrc t0, fflags, ra
+  .word 0x0010c2f3          # funct3 4: csrrci's encoding without its i bit
+  csrrwi t0, fflags, 1
+  csrrsi t0, fflags, 1
+  csrrci t0, fflags, 1
+  .word 0x10500073          # funct3 0: wfi
+  csrr t0, fflags
+  csrw fflags, ra
+  li a0, 0
+  li a7, 93
+  ecall
@@ -0,0 +1,159 @@
+# Exercises the instruction pairs the translation cache fuses, in a loop hot
+# enough for the JIT to compile it, then writes all registers to stdout.
+#
+# Rebuild with:
+#   llvm-mc -triple=riscv64 -filetype=obj fusion.s -o fusion.o
+#   ld.lld -o fusion fusion.o
+#
+# Offsets within .text are fixed with .org so the auipc pairs hit the
+# edges of the 12-bit low part (0x7f8, 0x7fc, -0x800 and 0x800). Loads stay
+# aligned: the interpreter does not do misaligned ones.
+
+.text
+.p2align 12
+.globl _start
+_start:
+  li s1, 0
+  li s2, 0
+  li s5, 40
+loop:
+  j body
+body_done:
+  # branches against a constant: li + beq/bne/blt, the constant on either
+  # side, taken on some iterations and not others
+  li t3, 5
+  beq s1, t3, 1f
+  addi s2, s2, 1
+1:
+  li t3, 7
+  bne s1, t3, 1f
+  addi s2, s2, 2
+1:
+  li t3, 9
+  blt s1, t3, 1f
+  addi s2, s2, 3
+1:
+  li t3, 11
+  blt t3, s1, 1f
+  addi s2, s2, 4
+1:
+  sub s4, zero, s1
+  li t3, -13
+  blt s4, t3, 1f
+  addi s2, s2, 5
+1:
+  li t3, -17
+  blt t3, s4, 1f
+  addi s2, s2, 6
+1:
+  slli t4, s2, 3
+  add s2, s2, t4
+  addi s1, s1, 1
+  blt s1, s5, loop
+
+  la t0, regs
+  sd x1, 8(t0)
+  sd x2, 16(t0)
+  sd x3, 24(t0)
+  sd x4, 32(t0)
+  sd x5, 40(t0)
+  sd x6, 48(t0)
+  sd x7, 56(t0)
+  sd x8, 64(t0)
+  sd x9, 72(t0)
+  sd x10, 80(t0)
+  sd x11, 88(t0)
+  sd x12, 96(t0)
+  sd x13, 104(t0)
+  sd x14, 112(t0)
+  sd x15, 120(t0)
+  sd x16, 128(t0)
+  sd x17, 136(t0)
+  sd x18, 144(t0)
+  sd x19, 152(t0)
+  sd x20, 160(t0)
+  sd x21, 168(t0)
+  sd x22, 176(t0)
+  sd x23, 184(t0)
+  sd x24, 192(t0)
+  sd x25, 200(t0)
+  sd x26, 208(t0)
+  sd x27, 216(t0)
+  sd x28, 224(t0)
+  sd x29, 232(t0)
+  sd x30, 240(t0)
+  sd x31, 248(t0)
+  li a0, 1
+  mv a1, t0
+  li a2, 256
+  li a7, 64
+  ecall
+  li a0, 0
+  li a7, 93
+  ecall
+
+.org 0x300
+  addi a0, a0, 4
+  ret
+  .quad 0x0f1e2d3c4b5a6978
+
+.org 0x400
+body:
+  # auipc + jalr and auipc + ld, low parts 0x7fc, 0x800 and 0x7f8
+  auipc t1, 0
+  jalr ra, 0x7fc(t1)
+  auipc t1, 1
+  jalr ra, -0x800(t1)
+  auipc t2, 0
+  ld a1, 0x7f8(t2)
+  add s2, s2, a1
+  nop
+  auipc t2, 1
+  ld a1, -0x800(t2)
+  add s2, s2, a1
+  add s2, s2, t1
+  add s2, s2, t2
+  # lui + addiw with a negative low part, lui + addi, and one that does
+  # not fit in 32 bits and must not be fused
+  lui a3, 0x12346
+  addiw a3, a3, -1
+  lui a4, 0x7ffff
+  addi a4, a4, 0x7ff
+  lui a5, 0x80000
+  addi a5, a5, -1
+  add s2, s2, a3
+  add s2, s2, a4
+  add s2, s2, a5
+  # slli + srli by the same amount
+  slli a6, s2, 32
+  srli a6, a6, 32
+  slli a7, s2, 1
+  srli a7, a7, 1
+  add s3, a6, a7
+  j 2f
+
+.org 0xb00
+2:
+  # low part -0x800
+  auipc t1, 0
+  jalr ra, -0x800(t1)
+  auipc t2, 0
+  ld a2, -0x800(t2)
+  add s2, s2, a2
+  add s2, s2, a0
+  j body_done
+
+.org 0xbfc
+  addi a0, a0, 1
+  ret
+.org 0xc08
+  addi a0, a0, 2
+  ret
+.org 0xc10
+  .quad 0x0123456789abcdef
+  .quad 0xfedcba9876543210
+  .quad 0x1122334455667788
+
+.data
+.p2align 3
+regs: .zero 256
@@ -0,0 +1,85 @@
+# Touches guest addresses at and above 1 << 39, which are neither program
+# memory nor the stack. With no arguments, passes them to write(), and them
+# and the end of program memory to openat() as paths, and exits with the
+# number of calls that did not fail with EFAULT. With one argument
+# loads from 1 << 39, with two stores to -8, either way after running the
+# access on the stack often enough for the JIT to compile it.
+#
+# Rebuild with:
+#   llvm-mc -triple=riscv64 -filetype=obj wild.s -o wild.o
+#   ld.lld -o wild wild.o
+
+.text
+.globl _start
+_start:
+  ld s4, 0(sp)       # argc
+  li s1, 1
+  slli s1, s1, 39
+  li t0, 1
+  beq s4, t0, probe
+  li t0, 2
+  beq s4, t0, access
+  li s1, -8
+  j access
+
+probe:
+  li s2, 0
+  mv a1, s1
+  jal write
+  li a1, 0xbffdf00000
+  jal write
+  li a1, 1
+  slli a1, a1, 63
+  jal write
+  li a1, -16
+  jal write
+  mv a1, s1
+  jal open
+  li a1, 1
+  slli a1, a1, 24
+  jal open
+  li a1, 1
+  slli a1, a1, 24
+  addi a1, a1, -1     # the last byte of program memory, not NUL
+  li t0, 1
+  sb t0, 0(a1)
+  jal open
+  mv a0, s2
+  li a7, 93
+  ecall
+
+# write(1, a1, 16) and openat(AT_FDCWD, a1, 0), counting in s2 the calls
+# that do not return -EFAULT
+write:
+  li a0, 1
+  li a2, 16
+  li a7, 64
+  j check
+open:
+  li a0, -100
+  li a2, 0
+  li a7, 56
+check:
+  ecall
+  addi a0, a0, 14
+  beq a0, zero, 1f
+  addi s2, s2, 1
+1:
+  ret
+
+access:
+  li s3, 40
+loop:
+  mv t0, sp
+  bne s3, zero, 1f
+  mv t0, s1
+1:
+  li t1, 3
+  blt s4, t1, 2f
+  sd s4, 0(t0)
+  j 3f
+2:
+  ld a0, 0(t0)
+3:
+  addi s3, s3, -1
+  j loop
@@ -0,0 +1,38 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// The instruction trace and the Spike commit log are buffered; whatever is
+// buffered when the guest exits on its own has to be written out too.
+
+use std::process::Command;
+
+const GUEST: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/guest/fusion");
+
+/// What riscv-um writes to stderr running the guest with these options
+fn stderr(options: &[&str]) -> String {
+    let out = Command::new(env!("CARGO_BIN_EXE_riscv-um"))
+        .args(options)
+        .arg(GUEST)
+        .output()
+        .expect("cannot run riscv-um");
+    assert!(out.status.success(), "riscv-um {:?} failed", options);
+    String::from_utf8(out.stderr).unwrap()
+}
+
+#[test]
+fn trace_is_written_at_exit() {
+    // _start is the three instructions setting up the loop
+    let log = stderr(&["--trace=_start"]);
+    assert_eq!(log.lines().count(), 3, "{:?}", log);
+    assert!(log.starts_with("0x12000 <_start>"), "{:?}", log);
+    assert!(log.contains("li s5, 40"), "{:?}", log);
+}
+
+#[test]
+fn commit_log_is_written_at_exit() {
+    // filtered, so that no ecall flushes it on the way
+    let log = stderr(&["--log-commits", "--trace=_start"]);
+    let lines: Vec<_> = log.lines().collect();
+    assert_eq!(lines.len(), 3, "{:?}", log);
+    assert_eq!(lines[0], "core   0: 0 0x0000000000012000 (0x00000493) x9  0x0000000000000000");
+    assert_eq!(lines[2], "core   0: 0 0x0000000000012008 (0x02800a93) x21 0x0000000000000028");
+}
@@ -0,0 +1,59 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+// Guest addresses outside program memory and the stack must never reach
+// host memory: the guest in tests/guest/wild.s hands addresses at and above
+// 1 << 39 to write() and openat(), and a path running off the end of program
+// memory to openat(), which all have to fail with EFAULT, and --strace has
+// to print them as bare pointers. Loads and stores there have to crash the
+// guest with SIGSEGV however it is run.
+
+use std::process::Command;
+use std::process::Output;
+
+const GUEST: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/guest/wild");
+
+fn run(options: &[&str], args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_riscv-um"))
+        .args(options)
+        .arg(GUEST)
+        .args(args)
+        .output()
+        .expect("cannot run riscv-um")
+}
+
+fn modes() -> Vec<Vec<&'static str>> {
+    let mut modes = vec![vec![], vec!["--jit"]];
+    if cfg!(feature = "cranelift") {
+        modes.push(vec!["--jit=cranelift"]);
+    }
+    modes
+}
+
+#[test]
+fn syscalls_fail_with_efault() {
+    let out = run(&[], &[]);
+    assert_eq!(out.status.code(), Some(0), "{} calls did not fail with EFAULT", out.status);
+    assert!(out.stdout.is_empty());
+}
+
+#[test]
+fn loads_and_stores_crash() {
+    for mode in modes() {
+        for (args, addr) in [(&["load"][..], "0x8000000000"), (&["store", "store"][..], "0xfffffffffffffff8")] {
+            let out = run(&mode, args);
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            assert_eq!(out.status.code(), Some(128 + libc::SIGSEGV), "{:?} {:?}: {}", mode, args, stderr);
+            let report = format!("guest crashed: access to unmapped address {} (SIGSEGV)", addr);
+            assert!(stderr.contains(&report), "{:?} {:?}: {}", mode, args, stderr);
+        }
+    }
+}
+
+#[test]
+fn strace_prints_bad_pointers() {
+    let out = run(&["--strace"], &[]);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr);
+    assert!(stderr.contains("write(1,0x8000000000,16) = -1 errno=14"), "{}", stderr);
+    assert!(stderr.contains("openat(AT_FDCWD,0xffffff,O_RDONLY,0) = -1 errno=14"), "{}", stderr);
+}